@@ -0,0 +1,67 @@
+// Little endian encoding shared by the transaction log and snapshots.
+// Readers advance the input past what they decoded, and return None if it's cut short
+
+pub fn put_i32(v: i32, out: &mut Vec<u8>) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_i64(v: i64, out: &mut Vec<u8>) {
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+pub fn put_str(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len { return None; }
+    let (head, rest) = input.split_at(len);
+    *input = rest;
+    Some(head)
+}
+
+pub fn take_u8(input: &mut &[u8]) -> Option<u8> {
+    take(input, 1).map(|b| b[0])
+}
+
+pub fn take_i32(input: &mut &[u8]) -> Option<i32> {
+    take(input, 4).map(|b| i32::from_le_bytes(b.try_into().unwrap()))
+}
+
+pub fn take_i64(input: &mut &[u8]) -> Option<i64> {
+    take(input, 8).map(|b| i64::from_le_bytes(b.try_into().unwrap()))
+}
+
+pub fn take_str(input: &mut &[u8]) -> Option<String> {
+    let len = take_i32(input)? as u32 as usize;
+    take(input, len).and_then(|b| String::from_utf8(b.to_vec()).ok())
+}
+
+// [payload length: u32][adler32 of payload: u32][payload]
+pub fn put_record(payload: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&adler32(payload).to_le_bytes());
+    out.extend_from_slice(payload);
+}
+
+// the payload of the next record, None if it's torn or fails its checksum
+pub fn take_record<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let mut rest = *input;
+    let len = take_i32(&mut rest)? as u32 as usize;
+    let checksum = take_i32(&mut rest)? as u32;
+    let payload = take(&mut rest, len)?;
+    if adler32(payload) != checksum { return None; }
+    *input = rest;
+    Some(payload)
+}
+
+// same checksum ZooKeeper puts on its log entries
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in bytes {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
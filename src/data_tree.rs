@@ -0,0 +1,530 @@
+use crate::codec::{put_i32, put_i64, put_str, take_i32, take_i64, take_str};
+use crate::node::{Transaction, TxActions};
+use crate::watch::EventType;
+use std::collections::{BTreeSet, HashMap};
+
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ZkError {
+    NoNode,
+    NodeExists,
+    NotEmpty,
+    BadVersion,
+    BadArguments,
+    NoChildrenForEphemerals,
+    SessionExpired, // session is unknown or already closed
+    ConnectionLoss, // the write never got an answer, it may or may not have committed
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CreateMode {
+    Persistent,
+    Ephemeral, // deleted when the owning session closes
+    Sequential, // the leader appends a 10 digit counter taken from the parent's cversion
+    EphemeralSequential,
+}
+
+impl CreateMode {
+    pub fn is_ephemeral(&self) -> bool {
+        matches!(self, CreateMode::Ephemeral | CreateMode::EphemeralSequential)
+    }
+
+    pub fn is_sequential(&self) -> bool {
+        matches!(self, CreateMode::Sequential | CreateMode::EphemeralSequential)
+    }
+}
+
+// Same fields as ZooKeeper's Stat
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Stat {
+    pub czxid: i64, // zxid of the tx that created the znode
+    pub mzxid: i64, // zxid of the tx that last set its data
+    pub pzxid: i64, // zxid of the tx that last added/ removed a child
+    pub ctime: i64, // ms since unix epoch, stamped by the leader
+    pub mtime: i64,
+    pub version: i32, // number of data changes
+    pub cversion: i32, // number of child changes
+    pub data_length: i32,
+    pub num_children: i32,
+    pub ephemeral_owner: i64, // session id for ephemeral znodes, 0 otherwise
+}
+
+#[derive(Clone, Debug)]
+pub struct DataNode {
+    pub data: String,
+    pub children: BTreeSet<String>, // names only, not full paths
+    pub stat: Stat,
+}
+
+impl DataNode {
+    fn new(data: String, zxid: i64, time: i64, ephemeral_owner: i64) -> Self {
+        let stat = Stat {
+            czxid: zxid,
+            mzxid: zxid,
+            pzxid: zxid,
+            ctime: time,
+            mtime: time,
+            data_length: data.len() as i32,
+            ephemeral_owner,
+            ..Stat::default()
+        };
+        DataNode { data, children: BTreeSet::new(), stat }
+    }
+}
+
+// ZooKeeper style namespace, every node is addressed by its full path, "/" always exists
+#[derive(Clone, Debug)]
+pub struct DataTree {
+    nodes: HashMap<String, DataNode>,
+    sessions: HashMap<i64, BTreeSet<String>>, // open sessions and the ephemerals they own
+    changes: Vec<(EventType, String)>, // what the applied ops did, drained to fire watches
+}
+
+impl DataTree {
+    pub fn new() -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert("/".to_string(), DataNode::new(String::new(), 0, 0, 0));
+        DataTree { nodes, sessions: HashMap::new(), changes: Vec::new() }
+    }
+
+    // "/a/b" -> ("/a", "b"), the root has no parent
+    fn split_path(path: &str) -> Result<(&str, &str), ZkError> {
+        if !path.starts_with('/') || path == "/" || path.ends_with('/') || path.contains("//") {
+            return Err(ZkError::BadArguments);
+        }
+        let idx = path.rfind('/').unwrap();
+        let parent = if idx == 0 { "/" } else { &path[..idx] };
+        Ok((parent, &path[idx + 1..]))
+    }
+
+    // session_id is only looked at for ephemeral znodes
+    pub fn create(&mut self, path: &str, data: String, mode: &CreateMode, session_id: i64, zxid: i64, time: i64) -> Result<(), ZkError> {
+        let (parent, name) = DataTree::split_path(path)?;
+        if self.nodes.contains_key(path) {
+            return Err(ZkError::NodeExists);
+        }
+        let ephemeral_owner = if mode.is_ephemeral() {
+            if !self.sessions.contains_key(&session_id) { return Err(ZkError::SessionExpired); }
+            session_id
+        } else {
+            0
+        };
+        match self.nodes.get_mut(parent) {
+            None => return Err(ZkError::NoNode),
+            Some(p) => {
+                if p.stat.ephemeral_owner != 0 { return Err(ZkError::NoChildrenForEphemerals); }
+                p.children.insert(name.to_string());
+                DataTree::children_changed(p, zxid);
+            }
+        }
+        if ephemeral_owner != 0 {
+            self.sessions.get_mut(&ephemeral_owner).unwrap().insert(path.to_string());
+        }
+        self.nodes.insert(path.to_string(), DataNode::new(data, zxid, time, ephemeral_owner));
+        self.changes.push((EventType::NodeCreated, path.to_string()));
+        self.changes.push((EventType::NodeChildrenChanged, parent.to_string()));
+        Ok(())
+    }
+
+    // expected_version of -1 matches any version
+    pub fn set_data(&mut self, path: &str, data: String, expected_version: i32, zxid: i64, time: i64) -> Result<(), ZkError> {
+        match self.nodes.get_mut(path) {
+            None => Err(ZkError::NoNode),
+            Some(node) => {
+                if expected_version != -1 && expected_version != node.stat.version {
+                    return Err(ZkError::BadVersion);
+                }
+                node.stat.mzxid = zxid;
+                node.stat.mtime = time;
+                node.stat.version += 1;
+                node.stat.data_length = data.len() as i32;
+                node.data = data;
+                self.changes.push((EventType::NodeDataChanged, path.to_string()));
+                Ok(())
+            }
+        }
+    }
+
+    pub fn delete(&mut self, path: &str, expected_version: i32, zxid: i64) -> Result<(), ZkError> {
+        let (parent, name) = DataTree::split_path(path)?;
+        match self.nodes.get(path) {
+            None => return Err(ZkError::NoNode),
+            Some(node) => {
+                if expected_version != -1 && expected_version != node.stat.version {
+                    return Err(ZkError::BadVersion);
+                }
+                if !node.children.is_empty() { return Err(ZkError::NotEmpty); }
+            }
+        }
+        let node = self.nodes.remove(path).unwrap();
+        if let Some(owned) = self.sessions.get_mut(&node.stat.ephemeral_owner) {
+            owned.remove(path);
+        }
+        let p = self.nodes.get_mut(parent).unwrap();
+        p.children.remove(name);
+        DataTree::children_changed(p, zxid);
+        self.changes.push((EventType::NodeDeleted, path.to_string()));
+        self.changes.push((EventType::NodeChildrenChanged, parent.to_string()));
+        Ok(())
+    }
+
+    fn children_changed(parent: &mut DataNode, zxid: i64) {
+        parent.stat.cversion += 1;
+        parent.stat.pzxid = zxid;
+        parent.stat.num_children = parent.children.len() as i32;
+    }
+
+    pub fn create_session(&mut self, session_id: i64) -> Result<(), ZkError> {
+        if session_id == 0 || self.sessions.contains_key(&session_id) {
+            return Err(ZkError::BadArguments);
+        }
+        self.sessions.insert(session_id, BTreeSet::new());
+        Ok(())
+    }
+
+    // drops the session along with every ephemeral znode it owns
+    pub fn close_session(&mut self, session_id: i64, zxid: i64) -> Result<(), ZkError> {
+        match self.sessions.remove(&session_id) {
+            None => Err(ZkError::SessionExpired),
+            Some(owned) => {
+                // ephemerals can't have children, so any order works
+                for path in owned.iter() {
+                    self.delete(path, -1, zxid)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    pub fn take_changes(&mut self) -> Vec<(EventType, String)> {
+        std::mem::take(&mut self.changes)
+    }
+
+    pub fn get_data(&self, path: &str) -> Option<(String, Stat)> {
+        self.nodes.get(path).map(|node| (node.data.clone(), node.stat.clone()))
+    }
+
+    pub fn stat(&self, path: &str) -> Option<Stat> {
+        self.nodes.get(path).map(|node| node.stat.clone())
+    }
+
+    pub fn get_children(&self, path: &str) -> Result<Vec<String>, ZkError> {
+        match self.nodes.get(path) {
+            None => Err(ZkError::NoNode),
+            Some(node) => Ok(node.children.iter().cloned().collect()),
+        }
+    }
+
+    pub fn check_version(&self, path: &str, expected_version: i32) -> Result<(), ZkError> {
+        match self.nodes.get(path) {
+            None => Err(ZkError::NoNode),
+            Some(node) if expected_version != -1 && expected_version != node.stat.version => Err(ZkError::BadVersion),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // children and each session's ephemerals aren't written, they're rebuilt from the paths and owners
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        put_i32(self.sessions.len() as i32, out);
+        for session_id in self.sessions.keys() {
+            put_i64(*session_id, out);
+        }
+        put_i32(self.nodes.len() as i32, out);
+        for (path, node) in self.nodes.iter() {
+            put_str(path, out);
+            put_str(&node.data, out);
+            let stat = &node.stat;
+            for v in [stat.czxid, stat.mzxid, stat.pzxid, stat.ctime, stat.mtime, stat.ephemeral_owner] {
+                put_i64(v, out);
+            }
+            for v in [stat.version, stat.cversion, stat.data_length, stat.num_children] {
+                put_i32(v, out);
+            }
+        }
+    }
+
+    pub fn decode(input: &mut &[u8]) -> Option<DataTree> {
+        let mut tree = DataTree { nodes: HashMap::new(), sessions: HashMap::new(), changes: Vec::new() };
+        for _ in 0..take_i32(input)? {
+            tree.sessions.insert(take_i64(input)?, BTreeSet::new());
+        }
+        for _ in 0..take_i32(input)? {
+            let path = take_str(input)?;
+            let data = take_str(input)?;
+            let stat = Stat {
+                czxid: take_i64(input)?,
+                mzxid: take_i64(input)?,
+                pzxid: take_i64(input)?,
+                ctime: take_i64(input)?,
+                mtime: take_i64(input)?,
+                ephemeral_owner: take_i64(input)?,
+                version: take_i32(input)?,
+                cversion: take_i32(input)?,
+                data_length: take_i32(input)?,
+                num_children: take_i32(input)?,
+            };
+            tree.nodes.insert(path, DataNode { data, children: BTreeSet::new(), stat });
+        }
+        let paths: Vec<String> = tree.nodes.keys().filter(|p| p.as_str() != "/").cloned().collect();
+        for path in paths {
+            let (parent, name) = DataTree::split_path(&path).ok()?;
+            tree.nodes.get_mut(parent)?.children.insert(name.to_string());
+            let owner = tree.nodes[&path].stat.ephemeral_owner;
+            if owner != 0 {
+                tree.sessions.get_mut(&owner)?.insert(path);
+            }
+        }
+        if tree.nodes.contains_key("/") { Some(tree) } else { None }
+    }
+
+    pub fn process_tx(&mut self, tx: &Transaction) -> Result<(), ZkError> {
+        self.process_op(tx, tx.zxid, tx.time)
+    }
+
+    // ops of a multi have no zxid of their own, they all share the enclosing tx's
+    fn process_op(&mut self, op: &Transaction, zxid: i64, time: i64) -> Result<(), ZkError> {
+        match op.action {
+            TxActions::New => self.create(&op.key, op.val.clone(), &op.mode, op.session_id, zxid, time),
+            TxActions::Set => self.set_data(&op.key, op.val.clone(), op.version, zxid, time),
+            TxActions::Del => self.delete(&op.key, op.version, zxid),
+            TxActions::Check => self.check_version(&op.key, op.version),
+            TxActions::CreateSession => self.create_session(op.session_id),
+            TxActions::CloseSession => self.close_session(op.session_id, zxid),
+            TxActions::Multi => {
+                // all or nothing, every op is checked against the tree and the ops before it, only then are they applied
+                DataTree::check_multi(op)?;
+                PendingChanges::new().apply(self, op, zxid)?;
+                for sub_op in op.ops.iter() {
+                    self.process_op(sub_op, zxid, time)?;
+                }
+                Ok(())
+            }
+            TxActions::NA => Ok(()),
+        }
+    }
+
+    // like ZooKeeper's, a multi only takes creates, sets, deletes and checks. Sessions are tracked outside
+    // the tree too, so opening or closing one inside a multi would only half happen
+    fn check_multi(op: &Transaction) -> Result<(), ZkError> {
+        let allowed = |sub_op: &Transaction| matches!(sub_op.action, TxActions::New | TxActions::Set | TxActions::Del | TxActions::Check);
+        if op.ops.iter().all(allowed) { Ok(()) } else { Err(ZkError::BadArguments) }
+    }
+}
+
+// what checking an op needs to know about a znode, see PendingChanges
+#[derive(Clone, Debug)]
+struct ZnodeState {
+    version: i32,
+    cversion: i32,
+    num_children: i32,
+    ephemeral_owner: i64,
+}
+
+// an overlay entry as it was before an op changed it, to roll back a multi that fails halfway
+enum Saved {
+    Node(String, Option<(i64, Option<ZnodeState>)>),
+    Session(i64, Option<(i64, Option<BTreeSet<String>>)>),
+}
+
+// The tree as it will be once some txs that aren't applied to it yet are, kept as only what they change on top of it,
+// like ZooKeeper's outstandingChanges. Checking an op against it costs what the op touches, not a copy of the tree
+#[derive(Debug, Default)]
+pub struct PendingChanges {
+    nodes: HashMap<String, (i64, Option<ZnodeState>)>, // zxid of the last tx that changed the znode, None once it's deleted
+    sessions: HashMap<i64, (i64, Option<BTreeSet<String>>)>, // the ephemerals an open session owns, None once it's closed
+}
+
+impl PendingChanges {
+    pub fn new() -> Self {
+        PendingChanges::default()
+    }
+
+    fn node(&self, tree: &DataTree, path: &str) -> Option<ZnodeState> {
+        match self.nodes.get(path) {
+            Some((_, state)) => state.clone(),
+            None => tree.nodes.get(path).map(|node| ZnodeState {
+                version: node.stat.version,
+                cversion: node.stat.cversion,
+                num_children: node.children.len() as i32,
+                ephemeral_owner: node.stat.ephemeral_owner,
+            }),
+        }
+    }
+
+    fn session(&self, tree: &DataTree, session_id: i64) -> Option<BTreeSet<String>> {
+        match self.sessions.get(&session_id) {
+            Some((_, owned)) => owned.clone(),
+            None => tree.sessions.get(&session_id).cloned(),
+        }
+    }
+
+    fn set_node(&mut self, path: &str, zxid: i64, state: Option<ZnodeState>, saved: &mut Vec<Saved>) {
+        let before = self.nodes.insert(path.to_string(), (zxid, state));
+        saved.push(Saved::Node(path.to_string(), before));
+    }
+
+    fn set_session(&mut self, session_id: i64, zxid: i64, owned: Option<BTreeSet<String>>, saved: &mut Vec<Saved>) {
+        let before = self.sessions.insert(session_id, (zxid, owned));
+        saved.push(Saved::Session(session_id, before));
+    }
+
+    // checks op the same way the tree would run it and records what it changes under zxid,
+    // an op that fails leaves nothing behind, not even the ops of a multi before the one that failed
+    pub fn apply(&mut self, tree: &DataTree, op: &Transaction, zxid: i64) -> Result<(), ZkError> {
+        let mut saved = Vec::new();
+        let result = self.apply_op(tree, op, zxid, &mut saved);
+        if result.is_err() {
+            self.roll_back(saved);
+        }
+        result
+    }
+
+    // whether op would apply, without recording it
+    pub fn check(&mut self, tree: &DataTree, op: &Transaction) -> Result<(), ZkError> {
+        let mut saved = Vec::new();
+        let result = self.apply_op(tree, op, 0, &mut saved);
+        self.roll_back(saved);
+        result
+    }
+
+    fn roll_back(&mut self, saved: Vec<Saved>) {
+        for entry in saved.into_iter().rev() {
+            match entry {
+                Saved::Node(path, Some(before)) => { self.nodes.insert(path, before); }
+                Saved::Node(path, None) => { self.nodes.remove(&path); }
+                Saved::Session(session_id, Some(before)) => { self.sessions.insert(session_id, before); }
+                Saved::Session(session_id, None) => { self.sessions.remove(&session_id); }
+            }
+        }
+    }
+
+    // the same errors in the same order as DataTree's
+    fn apply_op(&mut self, tree: &DataTree, op: &Transaction, zxid: i64, saved: &mut Vec<Saved>) -> Result<(), ZkError> {
+        match op.action {
+            TxActions::New => {
+                let (parent, _) = DataTree::split_path(&op.key)?;
+                if self.node(tree, &op.key).is_some() {
+                    return Err(ZkError::NodeExists);
+                }
+                let owned = if op.mode.is_ephemeral() {
+                    Some(self.session(tree, op.session_id).ok_or(ZkError::SessionExpired)?)
+                } else {
+                    None
+                };
+                let mut p = self.node(tree, parent).ok_or(ZkError::NoNode)?;
+                if p.ephemeral_owner != 0 { return Err(ZkError::NoChildrenForEphemerals); }
+                p.cversion += 1;
+                p.num_children += 1;
+                self.set_node(parent, zxid, Some(p), saved);
+                let ephemeral_owner = match owned {
+                    Some(mut owned) => {
+                        owned.insert(op.key.clone());
+                        self.set_session(op.session_id, zxid, Some(owned), saved);
+                        op.session_id
+                    }
+                    None => 0,
+                };
+                self.set_node(&op.key, zxid, Some(ZnodeState { version: 0, cversion: 0, num_children: 0, ephemeral_owner }), saved);
+                Ok(())
+            }
+            TxActions::Set => {
+                let mut node = self.node(tree, &op.key).ok_or(ZkError::NoNode)?;
+                if op.version != -1 && op.version != node.version {
+                    return Err(ZkError::BadVersion);
+                }
+                node.version += 1;
+                self.set_node(&op.key, zxid, Some(node), saved);
+                Ok(())
+            }
+            TxActions::Del => self.delete(tree, &op.key, op.version, zxid, saved),
+            TxActions::Check => {
+                let node = self.node(tree, &op.key).ok_or(ZkError::NoNode)?;
+                if op.version != -1 && op.version != node.version {
+                    return Err(ZkError::BadVersion);
+                }
+                Ok(())
+            }
+            TxActions::CreateSession => {
+                if op.session_id == 0 || self.session(tree, op.session_id).is_some() {
+                    return Err(ZkError::BadArguments);
+                }
+                self.set_session(op.session_id, zxid, Some(BTreeSet::new()), saved);
+                Ok(())
+            }
+            TxActions::CloseSession => {
+                let owned = self.session(tree, op.session_id).ok_or(ZkError::SessionExpired)?;
+                self.set_session(op.session_id, zxid, None, saved);
+                for path in owned.iter() {
+                    self.delete(tree, path, -1, zxid, saved)?;
+                }
+                Ok(())
+            }
+            TxActions::Multi => {
+                DataTree::check_multi(op)?;
+                for sub_op in op.ops.iter() {
+                    self.apply_op(tree, sub_op, zxid, saved)?;
+                }
+                Ok(())
+            }
+            TxActions::NA => Ok(()),
+        }
+    }
+
+    fn delete(&mut self, tree: &DataTree, path: &str, expected_version: i32, zxid: i64, saved: &mut Vec<Saved>) -> Result<(), ZkError> {
+        let (parent, _) = DataTree::split_path(path)?;
+        let node = self.node(tree, path).ok_or(ZkError::NoNode)?;
+        if expected_version != -1 && expected_version != node.version {
+            return Err(ZkError::BadVersion);
+        }
+        if node.num_children != 0 { return Err(ZkError::NotEmpty); }
+        if let Some(mut owned) = self.session(tree, node.ephemeral_owner) {
+            owned.remove(path);
+            self.set_session(node.ephemeral_owner, zxid, Some(owned), saved);
+        }
+        let mut p = self.node(tree, parent).ok_or(ZkError::NoNode)?;
+        p.cversion += 1;
+        p.num_children -= 1;
+        self.set_node(parent, zxid, Some(p), saved);
+        self.set_node(path, zxid, None, saved);
+        Ok(())
+    }
+
+    // "/queue/item-" -> "/queue/item-0000000003" when /queue's cversion is 3
+    pub fn sequential_path(&self, tree: &DataTree, path: &str) -> Result<String, ZkError> {
+        let (parent, _) = DataTree::split_path(path)?;
+        match self.node(tree, parent) {
+            None => Err(ZkError::NoNode),
+            Some(p) => Ok(format!("{}{:010}", path, p.cversion)),
+        }
+    }
+
+    // leader only, gives sequential creates their final path before the tx is proposed,
+    // within a multi every op sees the effect of the ops before it
+    pub fn name_sequential(&mut self, tree: &DataTree, tx: &mut Transaction) -> Result<(), ZkError> {
+        match tx.action {
+            TxActions::New if tx.mode.is_sequential() => {
+                tx.key = self.sequential_path(tree, &tx.key)?;
+                Ok(())
+            }
+            TxActions::Multi => {
+                DataTree::check_multi(tx)?;
+                let mut saved = Vec::new();
+                let mut result = Ok(());
+                for op in tx.ops.iter_mut() {
+                    result = self.name_sequential(tree, op).and_then(|_| self.apply_op(tree, op, 0, &mut saved));
+                    if result.is_err() { break; }
+                }
+                // only naming them here, apply records them for real
+                self.roll_back(saved);
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // the tree caught up with every change up to zxid, those no longer need overlaying
+    pub fn applied(&mut self, zxid: i64) {
+        self.nodes.retain(|_, (changed, _)| *changed > zxid);
+        self.sessions.retain(|_, (changed, _)| *changed > zxid);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Like ZooKeeper's finalizeWait, how long a vote a quorum agrees on has to hold before it's final,
+// scaled to the 200 to 800ms messages take here
+pub const FINALIZE_WAIT: Duration = Duration::from_millis(1000);
+
+// How a node looks for a leader, the whole ensemble has to run the same one
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ElectionAlg {
+    Campaign, // a candidate asks the others to approve it and leads once a quorum did
+    Fast, // ZooKeeper's FastLeaderElection
+}
+
+// epoch, last zxid and id of the node voted for, compared in that order like ZooKeeper's totalOrderPredicate
+pub type Vote = (i32, i64, i32);
+
+// same quorum every phase uses, more than half the ensemble, so 2 of 3 and 3 of 4 or 5
+pub fn is_quorum(cluster_size: i32, count: usize) -> bool {
+    count as i32 > cluster_size / 2
+}
+
+// The vote tally of ZooKeeper's FastLeaderElection. Every looking node broadcasts its vote in a notification,
+// switches to any better vote it hears of in its round, and settles once a quorum agrees on the same vote.
+// Nodes that already follow or lead answer a looking one with the vote they settled on
+pub struct FastLeaderElection {
+    cluster_size: i32,
+    pub round: i64, // ZooKeeper's logicalclock, one more every time the node starts looking
+    pub vote: Vote,
+    received: HashMap<i32, Vote>, // votes of the looking nodes in this round, its own included
+    out_of_election: HashMap<i32, (Vote, bool)>, // votes of the nodes that follow or lead already, and whether they're leading
+    agreed_at: Option<Instant>, // since when a quorum agrees on vote
+}
+
+impl FastLeaderElection {
+    pub fn new(cluster_size: i32) -> Self {
+        FastLeaderElection { cluster_size, round: 0, vote: (0, 0, 0), received: HashMap::new(), out_of_election: HashMap::new(), agreed_at: None }
+    }
+
+    // a new round that starts out voting for itself
+    pub fn start(&mut self, id: i32, own: Vote) {
+        self.round += 1;
+        self.vote = own;
+        self.received = HashMap::from([(id, own)]);
+        self.out_of_election = HashMap::new();
+        self.agreed_at = None;
+    }
+
+    // the vote of another looking node, true if this node's vote changed and has to go out again.
+    // Votes of older rounds don't count, the sender has to be told about this one instead
+    pub fn receive(&mut self, id: i32, own: Vote, sender: i32, vote: Vote, round: i64) -> bool {
+        if round < self.round {
+            return false;
+        }
+        let mut changed = false;
+        if round > self.round {
+            // it's behind, everything heard in the old round is void
+            self.round = round;
+            self.received = HashMap::new();
+            self.vote = own.max(vote);
+            changed = true;
+        } else if vote > self.vote {
+            self.vote = vote;
+            changed = true;
+        }
+        if changed {
+            self.agreed_at = None;
+        }
+        self.received.insert(sender, vote);
+        self.received.insert(id, self.vote);
+        changed
+    }
+
+    // the vote a node that follows or leads settled on. If a quorum of those agree on a leader that says
+    // it's leading itself, there's nothing left to elect, that's the vote
+    pub fn receive_settled(&mut self, sender: i32, vote: Vote, round: i64, leading: bool) -> Option<Vote> {
+        self.out_of_election.insert(sender, (vote, leading));
+        let agreeing = self.out_of_election.values().filter(|(other, _)| other.2 == vote.2).count();
+        let leader_leads = self.out_of_election.get(&vote.2).is_some_and(|(_, leading)| *leading);
+        if !is_quorum(self.cluster_size, agreeing) || !leader_leads {
+            return None;
+        }
+        self.round = self.round.max(round);
+        self.vote = vote;
+        Some(vote)
+    }
+
+    // the vote, once a quorum agreed on it for FINALIZE_WAIT without a better one turning up
+    pub fn decided(&mut self, now: Instant) -> Option<Vote> {
+        let agreeing = self.received.values().filter(|vote| **vote == self.vote).count();
+        if !is_quorum(self.cluster_size, agreeing) {
+            self.agreed_at = None;
+            return None;
+        }
+        let agreed_at = *self.agreed_at.get_or_insert(now);
+        if now.duration_since(agreed_at) < FINALIZE_WAIT {
+            return None;
+        }
+        Some(self.vote)
+    }
+}
@@ -1,21 +1,80 @@
 extern crate alloc;
+use crate::election::ElectionAlg;
 use crate::zookeeper::Zookeeper;
+#[cfg(test)]
+use crate::data_tree::{CreateMode, DataTree, PendingChanges, ZkError};
+#[cfg(test)]
+use crate::election::{is_quorum, FastLeaderElection, FINALIZE_WAIT};
+#[cfg(test)]
+use crate::node::{legal_transition, make_zxid, LEASE, zxid_epoch, Node, NodeStatus, SyncMode, Transaction, TxActions, ZabPhase};
+#[cfg(test)]
+use crate::watch::{AddWatchMode, EventType, WatchManager, WatchedEvent};
+#[cfg(test)]
 use futures::future::join_all;
+#[cfg(test)]
+use crate::snapshot::Snapshot;
+#[cfg(test)]
+use crate::txn_log::{TxnLog, ACCEPTED_EPOCH, CURRENT_EPOCH};
+#[cfg(test)]
+use std::collections::HashSet;
+#[cfg(test)]
+use std::path::PathBuf;
+#[cfg(test)]
 use std::time::Duration;
+#[cfg(test)]
+use tokio::sync::broadcast;
+#[cfg(test)]
+use tokio::time::Instant;
+mod codec;
+mod data_tree;
+mod election;
 mod node;
+mod session;
+mod snapshot;
+mod txn_log;
+mod watch;
 mod zookeeper;
 
 #[tokio::main]
 async fn main() {
     let mut zk = Zookeeper::new(10);
+    // the fast leader election by default, --campaign for the older one to compare against
+    if std::env::args().any(|arg| arg == "--campaign") {
+        zk.set_election_alg(ElectionAlg::Campaign).await;
+    }
+    // --observer adds a non-voting server past the voting ones
+    if std::env::args().any(|arg| arg == "--observer") {
+        zk.add_observer();
+    }
     zk.run().await;
 }
 
+// a fresh data dir per test, so runs don't pick up each other's transaction logs
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("zk_test_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
 #[tokio::test]
 async fn election_test() {
     let zk_size = 10;
 
-    let mut zk = Zookeeper::new(zk_size);
+    let mut zk = Zookeeper::with_data_dir(zk_size, &test_dir("election_test"));
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    assert_eq!(zk.latest_leader, zk_size - 1);
+    zk.quit().await;
+    join_all(handlers).await;
+}
+#[tokio::test]
+async fn campaign_election_test() {
+    let zk_size = 10;
+
+    let mut zk = Zookeeper::with_data_dir(zk_size, &test_dir("campaign_election_test"));
+    zk.set_election_alg(ElectionAlg::Campaign).await;
     let handlers = zk.start_servers();
     tokio::time::sleep(Duration::from_secs(20)).await;
     zk.find_latest_leader().await;
@@ -23,10 +82,50 @@ async fn election_test() {
     zk.quit().await;
     join_all(handlers).await;
 }
+
+#[test]
+fn quorum_test() {
+    // an ensemble of 3 gets by without one of them, one of 5 without two
+    assert!(is_quorum(3, 2) && !is_quorum(3, 1));
+    assert!(is_quorum(4, 3) && !is_quorum(4, 2));
+    assert!(is_quorum(5, 3) && !is_quorum(5, 2));
+    assert!(is_quorum(10, 6) && !is_quorum(10, 5));
+}
+
+#[test]
+fn fast_leader_election_test() {
+    let mut election = FastLeaderElection::new(4);
+    let own = (1, 7, 0);
+    election.start(0, own);
+    assert_eq!(election.round, 1);
+    // a better vote of its round is taken over, a worse one isn't
+    assert!(election.receive(0, own, 3, (1, 8, 3), 1));
+    assert!(!election.receive(0, own, 4, (1, 8, 2), 1));
+    assert_eq!(election.vote, (1, 8, 3));
+    // 0 and 3 agree, a third one makes a quorum of 4
+    let now = Instant::now();
+    assert_eq!(election.decided(now), None);
+    election.receive(0, own, 2, (1, 8, 3), 1);
+    assert_eq!(election.decided(now), None);
+    assert_eq!(election.decided(now + FINALIZE_WAIT), Some((1, 8, 3)));
+
+    // a newer round throws away what was heard so far
+    assert!(election.receive(0, own, 4, (1, 5, 4), 2));
+    assert_eq!((election.round, election.vote), (2, (1, 7, 0)));
+    assert_eq!(election.decided(now + FINALIZE_WAIT), None);
+    assert!(!election.receive(0, own, 3, (1, 9, 3), 1));
+
+    // nodes that settled already are believed once a quorum of them agree and the leader says it's leading
+    assert_eq!(election.receive_settled(1, (2, 9, 3), 4, false), None);
+    assert_eq!(election.receive_settled(2, (2, 9, 3), 4, false), None);
+    assert_eq!(election.receive_settled(3, (2, 9, 3), 4, true), Some((2, 9, 3)));
+    assert_eq!(election.round, 4);
+}
+
 #[tokio::test]
 async fn leader_crash_test() {
     let zk_size = 10;
-    let mut zk = Zookeeper::new(zk_size);
+    let mut zk = Zookeeper::with_data_dir(zk_size, &test_dir("leader_crash_test"));
     let handlers = zk.start_servers();
     tokio::time::sleep(Duration::from_secs(20)).await;
 
@@ -44,22 +143,22 @@ async fn leader_crash_test() {
 
 #[tokio::test]
 async fn new_transactions() {
-    let mut zk = Zookeeper::new(10);
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("new_transactions"));
     let handlers = zk.start_servers();
 
     tokio::time::sleep(Duration::from_secs(20)).await;
-    let latest_leader = zk.find_latest_leader().await;
-    zk.new_transaction("k1".to_string(), "v1".to_string());
-    zk.new_transaction("k2".to_string(), "v2".to_string());
-    tokio::time::sleep(Duration::from_secs(2)).await;
+    zk.find_latest_leader().await;
+    zk.new_transaction("/k1".to_string(), "v1".to_string()).await.unwrap();
+    zk.new_transaction("/k2".to_string(), "v2".to_string()).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
 
-    for (id, node) in zk.servers.iter() {
+    for node in zk.servers.values() {
         let n = node.lock().await;
-        let tx1 = n.history.get(0).unwrap();
+        let tx1 = n.history.first().unwrap();
         let tx2 = n.history.get(1).unwrap();
-        assert_eq!(tx1.key, "k1".to_string());
+        assert_eq!(tx1.key, "/k1".to_string());
         assert_eq!(tx1.val, "v1".to_string());
-        assert_eq!(tx2.key, "k2".to_string());
+        assert_eq!(tx2.key, "/k2".to_string());
         assert_eq!(tx2.val, "v2".to_string());
     }
 
@@ -67,4 +166,1133 @@ async fn new_transactions() {
     join_all(handlers).await;
 }
 
+#[test]
+fn data_tree_test() {
+    let mut tree = DataTree::new();
+    assert_eq!(tree.create("/app/config", "x".to_string(), &CreateMode::Persistent, 0, 1, 0), Err(ZkError::NoNode));
+    assert_eq!(tree.create("app", "x".to_string(), &CreateMode::Persistent, 0, 1, 0), Err(ZkError::BadArguments));
+    tree.create("/app", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    tree.create("/app/config", String::new(), &CreateMode::Persistent, 0, 2, 0).unwrap();
+    tree.create("/app/config/db", "db1".to_string(), &CreateMode::Persistent, 0, 3, 0).unwrap();
+    tree.create("/app/config/cache", "c1".to_string(), &CreateMode::Persistent, 0, 4, 0).unwrap();
+    assert_eq!(tree.create("/app/config/db", "db2".to_string(), &CreateMode::Persistent, 0, 5, 0), Err(ZkError::NodeExists));
+
+    assert_eq!(tree.get_children("/app/config").unwrap(), vec!["cache".to_string(), "db".to_string()]);
+    assert_eq!(tree.get_children("/").unwrap(), vec!["app".to_string()]);
+    assert_eq!(tree.delete("/app/config", -1, 5), Err(ZkError::NotEmpty));
+
+    tree.set_data("/app/config/db", "db2".to_string(), -1, 5, 0).unwrap();
+    assert_eq!(tree.get_data("/app/config/db").unwrap().0, "db2".to_string());
+    assert_eq!(tree.set_data("/app/nothing", String::new(), -1, 6, 0), Err(ZkError::NoNode));
+
+    tree.delete("/app/config/db", -1, 6).unwrap();
+    assert!(tree.stat("/app/config/db").is_none());
+    assert_eq!(tree.get_children("/app/config").unwrap(), vec!["cache".to_string()]);
+}
+
+#[test]
+fn stat_test() {
+    let mut tree = DataTree::new();
+    tree.create("/app", String::new(), &CreateMode::Persistent, 0, 1, 100).unwrap();
+    tree.create("/app/db", "db1".to_string(), &CreateMode::Persistent, 0, 2, 200).unwrap();
+    tree.set_data("/app/db", "db12".to_string(), -1, 3, 300).unwrap();
+    tree.set_data("/app/db", "db123".to_string(), 1, 4, 400).unwrap();
+    assert_eq!(tree.set_data("/app/db", "db1234".to_string(), 1, 5, 500), Err(ZkError::BadVersion));
+    assert_eq!(tree.delete("/app/db", 0, 5), Err(ZkError::BadVersion));
+
+    let (data, stat) = tree.get_data("/app/db").unwrap();
+    assert_eq!(data, "db123".to_string());
+    assert_eq!((stat.czxid, stat.mzxid, stat.ctime, stat.mtime), (2, 4, 200, 400));
+    assert_eq!((stat.version, stat.data_length, stat.num_children), (2, 5, 0));
+
+    tree.create("/app/cache", String::new(), &CreateMode::Persistent, 0, 5, 500).unwrap();
+    tree.delete("/app/cache", 0, 6).unwrap();
+    let app = tree.stat("/app").unwrap();
+    assert_eq!((app.cversion, app.pzxid, app.num_children, app.version), (3, 6, 1, 0));
+    assert_eq!(app.mzxid, 1);
+}
+
+#[tokio::test]
+async fn committed_data_is_readable() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("committed_data_is_readable"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/app", "").await.unwrap();
+    zk.create("/app/k1", "v1").await.unwrap();
+    zk.create("/app/k2", "v2").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+    zk.delete("/app/k1", -1).await.unwrap();
+    zk.set("/app/k2", "v3", -1).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+
+    for id in zk.servers.keys() {
+        assert!(zk.exists(*id, "/app/k1").await.unwrap().is_none());
+        let (data, stat) = zk.get(*id, "/app/k2").await.unwrap().unwrap();
+        assert_eq!(data, "v3".to_string());
+        assert_eq!(stat.version, 1);
+        assert!(stat.czxid >> 32 > 0); // high bits carry the epoch
+        assert!(stat.mzxid > stat.czxid);
+        assert_eq!(zk.get_children(*id, "/app").await, Ok(vec!["k2".to_string()]));
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn conditional_writes() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("conditional_writes"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/lock", "free").await.unwrap();
+    // both expect version 0, only the first one can win
+    zk.set("/lock", "a", 0).await.unwrap();
+    assert_eq!(zk.set("/lock", "b", 0).await, Err(ZkError::BadVersion));
+    assert_eq!(zk.delete("/lock", 0).await, Err(ZkError::BadVersion));
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    for id in zk.servers.keys() {
+        let (data, stat) = zk.get(*id, "/lock").await.unwrap().unwrap();
+        assert_eq!(data, "a".to_string());
+        assert_eq!(stat.version, 1);
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn multi_test() {
+    let mut tree = DataTree::new();
+    tree.create("/cfg", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+
+    let mut tx = Transaction::multi(vec![
+        Transaction::check_op("/cfg", 0),
+        Transaction::create_op("/cfg/a", "1"),
+        Transaction::create_op("/cfg/b", "1"),
+        Transaction::set_op("/cfg", "v1", 0),
+    ]);
+    tx.zxid = 2;
+    tree.process_tx(&tx).unwrap();
+    assert_eq!(tree.get_children("/cfg").unwrap(), vec!["a".to_string(), "b".to_string()]);
+    let (data, stat) = tree.get_data("/cfg").unwrap();
+    assert_eq!((data, stat.mzxid, stat.pzxid), ("v1".to_string(), 2, 2));
+
+    // the last op fails, nothing before it may stick
+    let mut tx = Transaction::multi(vec![
+        Transaction::set_op("/cfg/a", "2", -1),
+        Transaction::delete_op("/cfg/b", -1),
+        Transaction::check_op("/cfg", 0),
+    ]);
+    tx.zxid = 3;
+    assert_eq!(tree.process_tx(&tx), Err(ZkError::BadVersion));
+    assert_eq!(tree.get_data("/cfg/a").unwrap().0, "1".to_string());
+    assert!(tree.stat("/cfg/b").is_some());
+
+    // sessions and nested multis are turned away before anything is applied
+    for op in [Transaction::session_op(TxActions::CreateSession, 7), Transaction::multi(vec![Transaction::create_op("/cfg/d", "")])] {
+        let mut tx = Transaction::multi(vec![Transaction::create_op("/cfg/c", ""), op]);
+        tx.zxid = 4;
+        assert_eq!(PendingChanges::new().name_sequential(&tree, &mut tx), Err(ZkError::BadArguments));
+        assert_eq!(PendingChanges::new().apply(&tree, &tx, 4), Err(ZkError::BadArguments));
+        assert_eq!(tree.process_tx(&tx), Err(ZkError::BadArguments));
+        assert!(tree.stat("/cfg/c").is_none());
+    }
+    assert_eq!(tree.create_session(7), Ok(()));
+}
+
+#[tokio::test]
+async fn multi_transactions() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("multi_transactions"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/cfg", "").await.unwrap();
+    zk.multi(vec![
+        Transaction::create_op("/cfg/a", "1"),
+        Transaction::create_op("/cfg/b", "1"),
+    ]).await.unwrap();
+    let result = zk.multi(vec![
+        Transaction::set_op("/cfg/a", "2", 0),
+        Transaction::set_op("/cfg/b", "2", 0),
+        Transaction::check_op("/cfg", 1), // /cfg was never set, so this fails
+    ]).await;
+    assert_eq!(result, Err(ZkError::BadVersion));
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    for id in zk.servers.keys() {
+        assert_eq!(zk.get(*id, "/cfg/a").await.unwrap().unwrap().0, "1".to_string());
+        assert_eq!(zk.get(*id, "/cfg/b").await.unwrap().unwrap().0, "1".to_string());
+        let stat = zk.exists(*id, "/cfg").await.unwrap().unwrap();
+        assert_eq!(stat.num_children, 2);
+        assert_eq!(stat.cversion, 2);
+    }
+
+    let leader = zk.servers[&zk.latest_leader].lock().await;
+    assert_eq!(leader.history.len(), 2); // the failed multi was never proposed
+    drop(leader);
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn ephemeral_test() {
+    let mut tree = DataTree::new();
+    tree.create("/services", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    assert_eq!(tree.create("/services/a", String::new(), &CreateMode::Ephemeral, 7, 2, 0), Err(ZkError::SessionExpired));
+
+    tree.create_session(7).unwrap();
+    tree.create("/services/a", String::new(), &CreateMode::Ephemeral, 7, 3, 0).unwrap();
+    tree.create("/services/b", String::new(), &CreateMode::Ephemeral, 7, 4, 0).unwrap();
+    assert_eq!(tree.stat("/services/a").unwrap().ephemeral_owner, 7);
+    assert_eq!(tree.create("/services/a/child", String::new(), &CreateMode::Persistent, 0, 5, 0), Err(ZkError::NoChildrenForEphemerals));
+
+    tree.delete("/services/b", -1, 5).unwrap();
+    tree.close_session(7, 6).unwrap();
+    assert!(tree.get_children("/services").unwrap().is_empty());
+    assert_eq!(tree.stat("/services").unwrap().pzxid, 6);
+    assert_eq!(tree.close_session(7, 7), Err(ZkError::SessionExpired));
+}
+
+#[tokio::test]
+async fn ephemeral_znodes() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("ephemeral_znodes"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let (s1, _) = zk.open_session(20000).await.unwrap();
+    let (s2, _) = zk.open_session(20000).await.unwrap();
+    zk.create("/services", "").await.unwrap();
+    zk.create_with_mode("/services/a", "host-a", CreateMode::Ephemeral, s1).await.unwrap();
+    zk.create_with_mode("/services/b", "host-b", CreateMode::Ephemeral, s2).await.unwrap();
+    // every write waits on its commit, keep the sessions from timing out meanwhile
+    zk.ping(s1);
+    zk.ping(s2);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    zk.ping(s2);
+    zk.close_session(s1).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+
+    for id in zk.servers.keys() {
+        assert_eq!(zk.get_children(*id, "/services").await, Ok(vec!["b".to_string()]));
+        assert_eq!(zk.exists(*id, "/services/b").await.unwrap().unwrap().ephemeral_owner, s2);
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn session_expiry() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("session_expiry"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/workers", "").await.unwrap();
+    let (long, timeout) = zk.open_session(60000).await.unwrap();
+    assert_eq!(timeout, session::MAX_SESSION_TIMEOUT); // above the maximum, gets negotiated down
+    // each write waits on its commit, so the short sessions are opened right before their ephemerals
+    let (alive, _) = zk.open_session(6000).await.unwrap();
+    zk.create_with_mode("/workers/alive", "", CreateMode::Ephemeral, alive).await.unwrap();
+    let (idle, _) = zk.open_session(5000).await.unwrap();
+    zk.create_with_mode("/workers/idle", "", CreateMode::Ephemeral, idle).await.unwrap();
+
+    for _ in 0..25 {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        zk.ping(alive);
+        zk.ping(long);
+    }
+
+    for id in zk.servers.keys() {
+        assert_eq!(zk.get_children(*id, "/workers").await, Ok(vec!["alive".to_string()]));
+        assert_eq!(zk.session_timeout(*id, idle).await, None);
+        assert_eq!(zk.session_timeout(*id, alive).await, Some(6000));
+        assert_eq!(zk.session_timeout(*id, long).await, Some(session::MAX_SESSION_TIMEOUT));
+    }
+    // the expired session can't leave watches behind, on any server
+    let follower = (zk.latest_leader + 1) % 10;
+    assert_eq!(zk.add_watch(follower, idle, "/workers", AddWatchMode::Persistent).await, Err(ZkError::SessionExpired));
+    // once a server said so its channel is dropped, later tries don't get as far as a server
+    assert_eq!(zk.exists_and_watch(zk.latest_leader, idle, "/workers").await, Err(ZkError::SessionExpired));
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn sequential_test() {
+    let mut tree = DataTree::new();
+    tree.create("/queue", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    tree.create("/queue/other", String::new(), &CreateMode::Persistent, 0, 2, 0).unwrap();
+    let mut pending = PendingChanges::new();
+    assert_eq!(pending.sequential_path(&tree, "/queue/item-"), Ok("/queue/item-0000000001".to_string()));
+    assert_eq!(pending.sequential_path(&tree, "/missing/item-"), Err(ZkError::NoNode));
+
+    // every op in a multi sees the counter bumped by the ones before it
+    let mut tx = Transaction::multi(vec![
+        Transaction::create_mode_op("/queue/item-", "", CreateMode::Sequential, 0),
+        Transaction::create_mode_op("/queue/item-", "", CreateMode::Sequential, 0),
+    ]);
+    pending.name_sequential(&tree, &mut tx).unwrap();
+    assert_eq!(tx.ops[0].key, "/queue/item-0000000001".to_string());
+    assert_eq!(tx.ops[1].key, "/queue/item-0000000002".to_string());
+}
+
+#[test]
+fn pending_changes_test() {
+    let mut tree = DataTree::new();
+    tree.create("/cfg", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    tree.create_session(7).unwrap();
+
+    // proposals not applied yet are checked against each other, not just against the tree
+    let mut pending = PendingChanges::new();
+    pending.apply(&tree, &Transaction::set_op("/cfg", "a", 0), 2).unwrap();
+    assert_eq!(pending.apply(&tree, &Transaction::set_op("/cfg", "b", 0), 3), Err(ZkError::BadVersion));
+    pending.apply(&tree, &Transaction::create_mode_op("/cfg/e", "", CreateMode::Ephemeral, 7), 3).unwrap();
+    assert_eq!(pending.apply(&tree, &Transaction::delete_op("/cfg", -1), 4), Err(ZkError::NotEmpty));
+    let mut seq = Transaction::create_mode_op("/cfg/s-", "", CreateMode::Sequential, 0);
+    pending.name_sequential(&tree, &mut seq).unwrap();
+    assert_eq!(seq.key, "/cfg/s-0000000001");
+    // a write that's only checked, e.g. one the leader then fails to log, isn't recorded
+    assert_eq!(pending.check(&tree, &Transaction::create_op("/cfg/c", "")), Ok(()));
+    assert_eq!(pending.check(&tree, &Transaction::create_op("/cfg/c", "")), Ok(()));
+
+    // closing the session takes its ephemerals along, and a failed multi leaves nothing behind
+    pending.apply(&tree, &Transaction::session_op(TxActions::CloseSession, 7), 4).unwrap();
+    let multi = Transaction::multi(vec![Transaction::create_op("/cfg/x", ""), Transaction::check_op("/cfg", 0)]);
+    assert_eq!(pending.apply(&tree, &multi, 5), Err(ZkError::BadVersion));
+    pending.apply(&tree, &Transaction::delete_op("/cfg", 1), 5).unwrap();
+
+    // once the tree applied them they're dropped, and it's checked against the tree alone again
+    pending.applied(5);
+    assert_eq!(pending.apply(&tree, &Transaction::set_op("/cfg", "c", 0), 6), Ok(()));
+}
+
+#[tokio::test]
+async fn sequential_znodes() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("sequential_znodes"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let (session, _) = zk.open_session(20000).await.unwrap();
+    zk.create("/locks", "").await.unwrap();
+    zk.create_with_mode("/locks/lock-", "a", CreateMode::Sequential, 0).await.unwrap();
+    zk.create_with_mode("/locks/lock-", "b", CreateMode::EphemeralSequential, session).await.unwrap();
+    zk.delete("/locks/lock-0000000000", -1).await.unwrap();
+    zk.create_with_mode("/locks/lock-", "c", CreateMode::Sequential, 0).await.unwrap();
+    zk.ping(session);
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    for id in zk.servers.keys() {
+        assert_eq!(zk.get_children(*id, "/locks").await, Ok(vec!["lock-0000000001".to_string(), "lock-0000000003".to_string()]));
+        assert_eq!(zk.exists(*id, "/locks/lock-0000000001").await.unwrap().unwrap().ephemeral_owner, session);
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn watch_manager_test() {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut watches = WatchManager::new();
+    let mut tree = DataTree::new();
+    watches.add_data_watch("/app", 1, sender.clone());
+    watches.add_child_watch("/", 1, sender.clone());
+    watches.add_data_watch("/other", 2, sender.clone());
+
+    tree.create("/app", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    tree.set_data("/app", "x".to_string(), -1, 2, 0).unwrap();
+    for (event_type, path) in tree.take_changes() {
+        watches.trigger(event_type, &path);
+    }
+    // one shot, the data change after the creation isn't delivered
+    assert_eq!(receiver.try_recv(), Ok(WatchedEvent { event_type: EventType::NodeCreated, path: "/app".to_string() }));
+    assert_eq!(receiver.try_recv(), Ok(WatchedEvent { event_type: EventType::NodeChildrenChanged, path: "/".to_string() }));
+    assert!(receiver.try_recv().is_err());
+
+    watches.remove_session(2);
+    tree.create("/other", String::new(), &CreateMode::Persistent, 0, 3, 0).unwrap();
+    for (event_type, path) in tree.take_changes() {
+        watches.trigger(event_type, &path);
+    }
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn watch_notifications() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("watch_notifications"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let (session, _) = zk.open_session(20000).await.unwrap();
+    let follower = (zk.latest_leader + 1) % 10;
+    zk.create("/cfg", "v0").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+    zk.ping(session);
+
+    zk.get_and_watch(follower, session, "/cfg").await.unwrap().unwrap();
+    zk.get_children_and_watch(follower, session, "/cfg").await.unwrap();
+    assert!(zk.exists_and_watch(follower, session, "/cfg/db").await.unwrap().is_none());
+    zk.set("/cfg", "v1", -1).await.unwrap();
+    zk.create("/cfg/db", "").await.unwrap();
+    zk.set("/cfg", "v2", -1).await.unwrap(); // the data watch already fired
+    // every write waits on its commit, keep the session from timing out meanwhile
+    zk.ping(session);
+    tokio::time::sleep(Duration::from_secs(8)).await;
+
+    let events = zk.poll_events(session);
+    assert_eq!(events, vec![
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/cfg".to_string() },
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/cfg/db".to_string() },
+        WatchedEvent { event_type: EventType::NodeChildrenChanged, path: "/cfg".to_string() },
+    ]);
+    // a closed or unknown session can't leave watches
+    zk.close_session(session).await.unwrap();
+    assert_eq!(zk.get_and_watch(follower, session, "/cfg").await, Err(ZkError::SessionExpired));
+    assert_eq!(zk.add_watch(follower, -7, "/cfg", AddWatchMode::Persistent).await, Err(ZkError::SessionExpired));
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn persistent_watch_test() {
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut watches = WatchManager::new();
+    let mut tree = DataTree::new();
+    watches.add_watch("/app", AddWatchMode::Persistent, 1, sender.clone());
+    watches.add_watch("/", AddWatchMode::PersistentRecursive, 2, sender.clone());
+
+    tree.create("/app", String::new(), &CreateMode::Persistent, 0, 1, 0).unwrap();
+    tree.set_data("/app", "x".to_string(), -1, 2, 0).unwrap();
+    tree.set_data("/app", "y".to_string(), -1, 3, 0).unwrap();
+    tree.create("/app/a", String::new(), &CreateMode::Persistent, 0, 4, 0).unwrap();
+    for (event_type, path) in tree.take_changes() {
+        watches.trigger(event_type, &path);
+    }
+    let mut events = Vec::new();
+    while let Ok(event) = receiver.try_recv() {
+        events.push(event);
+    }
+    // recursive watches skip child events, so "/" changing children reaches nobody
+    assert_eq!(events, vec![
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/app".to_string() },
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/app/a".to_string() },
+        WatchedEvent { event_type: EventType::NodeChildrenChanged, path: "/app".to_string() },
+    ]);
+
+    watches.remove_watches("/app", 1);
+    watches.remove_session(2);
+    tree.set_data("/app/a", "z".to_string(), -1, 5, 0).unwrap();
+    tree.delete("/app/a", -1, 6).unwrap();
+    for (event_type, path) in tree.take_changes() {
+        watches.trigger(event_type, &path);
+    }
+    assert!(receiver.try_recv().is_err());
+}
+
+#[tokio::test]
+async fn persistent_watches() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("persistent_watches"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let (session, _) = zk.open_session(20000).await.unwrap();
+    let follower = (zk.latest_leader + 1) % 10;
+    // the follower may not have applied the session yet
+    zk.sync(follower, "/").await.unwrap();
+    zk.add_watch(follower, session, "/svc", AddWatchMode::PersistentRecursive).await.unwrap();
+    zk.create("/svc", "v0").await.unwrap();
+    zk.create("/svc/a", "").await.unwrap();
+    zk.set("/svc/a", "v1", -1).await.unwrap();
+    zk.set("/svc/a", "v2", -1).await.unwrap();
+    zk.create("/other", "").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(10)).await;
+
+    let events = zk.poll_events(session);
+    assert_eq!(events, vec![
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/svc".to_string() },
+        WatchedEvent { event_type: EventType::NodeCreated, path: "/svc/a".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/svc/a".to_string() },
+        WatchedEvent { event_type: EventType::NodeDataChanged, path: "/svc/a".to_string() },
+    ]);
+
+    zk.remove_watches(follower, session, "/svc").await.unwrap();
+    zk.set("/svc/a", "v3", -1).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+    assert!(zk.poll_events(session).is_empty());
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn zxid_test() {
+    let zxid = make_zxid(2, 5);
+    assert_eq!(zxid, 0x2_0000_0005);
+    assert_eq!(zxid_epoch(zxid), 2);
+    // any zxid of a later epoch is newer, however far the old epoch's counter got
+    assert!(make_zxid(3, 1) > make_zxid(2, 0xffff_ffff));
+    assert!(make_zxid(2, 6) > zxid);
+}
+
+#[tokio::test]
+async fn zxid_rollover() {
+    let mut zk = Zookeeper::with_data_dir(5, &test_dir("zxid_rollover"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let leader = zk.latest_leader;
+    let epoch = {
+        let mut n = zk.servers[&leader].lock().await;
+        let mut last = Transaction::new();
+        last.zxid = make_zxid(n.current_epoch, 0xffff_ffff);
+        n.history.push(last);
+        n.current_epoch
+    };
+    // the leader has no zxid left for it, it steps down instead of turning every write away
+    assert_eq!(zk.write(Transaction::create_op("/r", "")).await, Err(ZkError::ConnectionLoss));
+    assert_ne!(zk.servers[&leader].lock().await.phase, ZabPhase::Broadcast);
+
+    tokio::time::sleep(Duration::from_secs(25)).await;
+    zk.find_latest_leader().await;
+    let zxid = zk.write(Transaction::create_op("/r", "")).await.unwrap();
+    assert!(zxid_epoch(zxid) > epoch);
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn txn_log_test() {
+    let dir = test_dir("txn_log_test");
+    let mut tx = Transaction::multi(vec![Transaction::create_mode_op("/a", "x", CreateMode::EphemeralSequential, 7), Transaction::check_op("/b", 2)]);
+    tx.zxid = make_zxid(3, 1);
+    tx.time = 42;
+    {
+        let (mut log, snapshot, history) = TxnLog::open(&dir).unwrap();
+        assert!(snapshot.is_none() && history.is_empty());
+        log.append(&tx).unwrap();
+        log.append(&Transaction::set_op("/a", "y", 0)).unwrap();
+    }
+    // a torn record from a crash mid append is dropped, everything before it survives
+    let log_file = dir.join("log.1");
+    let len = std::fs::metadata(&log_file).unwrap().len();
+    std::fs::OpenOptions::new().write(true).open(&log_file).unwrap().set_len(len - 3).unwrap();
+
+    let (mut log, _, history) = TxnLog::open(&dir).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].zxid, tx.zxid);
+    assert_eq!(history[0].time, 42);
+    assert_eq!(history[0].ops[0].mode, CreateMode::EphemeralSequential);
+    assert_eq!(history[0].ops[0].session_id, 7);
+    assert_eq!(history[0].ops[1].version, 2);
+
+    let mut delete = Transaction::delete_op("/a", -1);
+    delete.zxid = 1;
+    log.rewrite(1, &[]).unwrap();
+    log.append(&delete).unwrap();
+    let (_, _, history) = TxnLog::open(&dir).unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].key, "/a");
+}
+
+#[test]
+fn epoch_files_test() {
+    let dir = test_dir("epoch_files_test");
+    let (log, _, _) = TxnLog::open(&dir).unwrap();
+    assert_eq!(log.read_epoch(ACCEPTED_EPOCH).unwrap(), None);
+    log.write_epoch(ACCEPTED_EPOCH, 4).unwrap();
+    log.write_epoch(CURRENT_EPOCH, 3).unwrap();
+    log.write_epoch(ACCEPTED_EPOCH, 5).unwrap();
+
+    let (log, _, _) = TxnLog::open(&dir).unwrap();
+    assert_eq!(log.read_epoch(ACCEPTED_EPOCH).unwrap(), Some(5));
+    assert_eq!(log.read_epoch(CURRENT_EPOCH).unwrap(), Some(3));
+}
+
+#[test]
+fn snapshot_test() {
+    let mut tree = DataTree::new();
+    tree.create_session(7).unwrap();
+    tree.create("/app", "x".to_string(), &CreateMode::Persistent, 0, 1, 10).unwrap();
+    tree.create("/app/lock", String::new(), &CreateMode::Ephemeral, 7, 2, 20).unwrap();
+    tree.set_data("/app", "y".to_string(), -1, 3, 30).unwrap();
+    let snapshot = Snapshot { zxid: 3, data: tree.clone(), sessions: vec![(7, 4000)] };
 
+    let restored = Snapshot::decode(&snapshot.encode()).unwrap();
+    assert_eq!((restored.zxid, restored.sessions.clone()), (3, vec![(7, 4000)]));
+    for path in ["/", "/app", "/app/lock"] {
+        assert_eq!(restored.data.get_data(path), tree.get_data(path));
+        assert_eq!(restored.data.get_children(path), tree.get_children(path));
+    }
+    // ephemerals are tied back to their session, so closing it still deletes them
+    let mut data = restored.data;
+    data.close_session(7, 4).unwrap();
+    assert!(data.stat("/app/lock").is_none());
+
+    let mut bytes = snapshot.encode();
+    bytes.pop();
+    assert!(Snapshot::decode(&bytes).is_none());
+}
+
+#[test]
+fn snapshot_log_test() {
+    let dir = test_dir("snapshot_log_test");
+    let (mut log, _, _) = TxnLog::open(&dir).unwrap();
+    let mut tree = DataTree::new();
+    for zxid in 1..=8 {
+        let mut tx = Transaction::create_op(&format!("/n{}", zxid), "");
+        tx.zxid = zxid;
+        log.append(&tx).unwrap();
+        tree.process_tx(&tx).unwrap();
+        // snapshot every other tx, starting a new segment right after each
+        if zxid % 2 == 0 {
+            TxnLog::save_snapshot(&dir, &Snapshot { zxid, data: tree.clone(), sessions: Vec::new() }).unwrap();
+            log.roll(zxid + 1).unwrap();
+        }
+    }
+    let mut tx = Transaction::create_op("/n9", "");
+    tx.zxid = 9;
+    log.append(&tx).unwrap();
+
+    // only the log after the newest snapshot is replayed
+    let (_, snapshot, history) = TxnLog::open(&dir).unwrap();
+    assert_eq!(snapshot.unwrap().zxid, 8);
+    assert_eq!(history.iter().map(|tx| tx.zxid).collect::<Vec<_>>(), vec![9]);
+
+    // a corrupt snapshot is skipped for the one before it, whose log suffix is still there
+    std::fs::write(dir.join("snapshot.8"), b"garbage").unwrap();
+    let (_, snapshot, history) = TxnLog::open(&dir).unwrap();
+    let snapshot = snapshot.unwrap();
+    assert_eq!(snapshot.zxid, 6);
+    assert!(snapshot.data.stat("/n6").is_some() && snapshot.data.stat("/n7").is_none());
+    assert_eq!(history.iter().map(|tx| tx.zxid).collect::<Vec<_>>(), vec![7, 8, 9]);
+
+    // keeping 3 snapshots (4, 6, 8) drops snapshot 2, and the segments of zxids 1 to 4 snapshot 4 covers
+    TxnLog::purge(&dir, 3).unwrap();
+    let mut files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().into_string().unwrap()).collect();
+    files.sort();
+    assert_eq!(files, vec!["log.5", "log.7", "log.9", "snapshot.4", "snapshot.6", "snapshot.8"]);
+}
+
+#[test]
+fn truncate_log_test() {
+    let dir = test_dir("truncate_log_test");
+    let (mut log, _, _) = TxnLog::open(&dir).unwrap();
+    let mut tree = DataTree::new();
+    for zxid in 1..=6 {
+        let mut tx = Transaction::create_op(&format!("/n{}", zxid), "");
+        tx.zxid = zxid;
+        log.append(&tx).unwrap();
+        tree.process_tx(&tx).unwrap();
+        if zxid % 2 == 0 && zxid < 6 {
+            TxnLog::save_snapshot(&dir, &Snapshot { zxid, data: tree.clone(), sessions: Vec::new() }).unwrap();
+            log.roll(zxid + 1).unwrap();
+        }
+    }
+
+    log.truncate(5).unwrap();
+    let (_, snapshot, history) = TxnLog::open(&dir).unwrap();
+    assert_eq!(snapshot.unwrap().zxid, 4);
+    assert_eq!(history.iter().map(|tx| tx.zxid).collect::<Vec<_>>(), vec![5]);
+
+    // a snapshot past the truncation point goes too, the reload starts from the one before it
+    log.truncate(3).unwrap();
+    let mut tx = Transaction::create_op("/m4", "");
+    tx.zxid = 4;
+    log.append(&tx).unwrap();
+    let (_, snapshot, history) = TxnLog::open(&dir).unwrap();
+    assert_eq!(snapshot.unwrap().zxid, 2);
+    assert_eq!(history.iter().map(|tx| (tx.zxid, tx.key.as_str())).collect::<Vec<_>>(), vec![(3, "/n3"), (4, "/m4")]);
+
+    // with snapshot 2 gone as well, the segment of zxids 1 and 2 would be needed, but it was purged
+    std::fs::remove_file(dir.join("log.1")).unwrap();
+    assert!(log.truncate(1).is_err());
+    assert_eq!(TxnLog::open(&dir).unwrap().2.len(), 2);
+}
+
+#[tokio::test]
+async fn committed_index_test() {
+    let dir = test_dir("committed_index_test");
+    let (sender, receiver) = broadcast::channel(10);
+    let mut n = Node::new(0, sender.clone(), receiver, 3, &dir);
+    for (i, path) in ["/a", "/b", "/c"].iter().enumerate() {
+        let mut tx = Transaction::create_op(path, "");
+        tx.zxid = make_zxid(1, i as i64 + 1);
+        n.log.append(&tx).unwrap();
+        n.history.push(tx);
+    }
+    // logged proposals aren't data until they're committed
+    assert!(n.data.stat("/a").is_none());
+    Node::execute_tx(&mut n, make_zxid(1, 2)).await;
+    assert_eq!((n.last_committed_zxid, n.last_applied), (make_zxid(1, 2), make_zxid(1, 2)));
+    assert!(n.data.stat("/b").is_some() && n.data.stat("/c").is_none());
+    drop(n);
+
+    // the commit index outlives a restart, the tail after it is still only proposed
+    let n = Node::new(0, sender.clone(), sender.subscribe(), 3, &dir);
+    assert_eq!(n.last_committed_zxid, make_zxid(1, 2));
+    assert_eq!(n.history.len(), 3);
+}
+
+#[tokio::test]
+async fn write_results() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("write_results"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let leader = zk.latest_leader;
+    // resolves once it's applied on the leader, with the zxid it committed under
+    let zxid = zk.write(Transaction::create_op("/w", "x")).await.unwrap();
+    assert!(zxid_epoch(zxid) > 0);
+    assert_eq!(zk.get(leader, "/w").await.unwrap().unwrap().1.czxid, zxid);
+    assert!(zk.write(Transaction::set_op("/w", "y", 0)).await.unwrap() > zxid);
+
+    assert_eq!(zk.write(Transaction::create_op("/w", "")).await, Err(ZkError::NodeExists));
+    assert_eq!(zk.write(Transaction::set_op("/w", "z", 0)).await, Err(ZkError::BadVersion));
+    assert_eq!(zk.write(Transaction::create_op("/missing/child", "")).await, Err(ZkError::NoNode));
+    // a follower passes writes on to its leader, and answers once it applied them itself
+    let follower = (leader + 1) % 10;
+    let zxid = zk.write_to(follower, Transaction::create_op("/f", "x")).await.unwrap();
+    assert_eq!(zk.get(follower, "/f").await.unwrap().unwrap().1.czxid, zxid);
+    assert_eq!(zk.write_to(follower, Transaction::create_op("/w", "")).await, Err(ZkError::NodeExists));
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn sync_and_lease_reads() {
+    let mut zk = Zookeeper::with_data_dir(5, &test_dir("sync_and_lease_reads"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let leader = zk.latest_leader;
+    let follower = (leader + 1) % 5;
+    // the follower may not have heard of the commit yet, after a sync it has
+    let zxid = zk.write(Transaction::create_op("/s", "x")).await.unwrap();
+    assert!(zk.sync(follower, "/s").await.unwrap() >= zxid);
+    assert_eq!(zk.get(follower, "/s").await.unwrap().unwrap().1.czxid, zxid);
+
+    assert!(zk.servers[&leader].lock().await.lease_until.is_some());
+    assert_eq!(zk.get_leased("/s").await.unwrap().unwrap().1.czxid, zxid);
+    // without a quorum acking its heartbeats its lease runs out, even if it's still leading
+    for id in (1..4).map(|i| (leader + i) % 5) {
+        zk.stop_server(id).await;
+    }
+    tokio::time::sleep(LEASE + Duration::from_secs(1)).await;
+    assert_eq!(zk.get_leased("/s").await, Err(ZkError::ConnectionLoss));
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn queued_writes() {
+    let mut zk = Zookeeper::with_data_dir(3, &test_dir("queued_writes"));
+    let handlers = zk.start_servers();
+
+    // nobody leads yet, server 0 holds on to it until it's up to date with whoever wins
+    let zxid = zk.write_to(0, Transaction::create_op("/q", "x")).await.unwrap();
+    assert_eq!(zk.get(0, "/q").await.unwrap().unwrap().1.czxid, zxid);
+
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    zk.find_latest_leader().await;
+    assert!(zk.exists(zk.latest_leader, "/q").await.unwrap().is_some());
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn lagging_node() {
+    let mut zk = Zookeeper::with_data_dir(3, &test_dir("lagging_node"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let follower = (zk.latest_leader + 1) % 3;
+    {
+        // the channel overflows while the follower's lock is held, it drops what it missed instead of its task
+        let node = zk.servers[&follower].clone();
+        let _n = node.lock().await;
+        for _ in 0..100 {
+            zk.ping(0);
+        }
+        tokio::time::sleep(Duration::from_secs(2)).await;
+    }
+    let zxid = zk.write_to(follower, Transaction::create_op("/a", "")).await.unwrap();
+    assert_eq!(zk.exists(follower, "/a").await.unwrap().unwrap().czxid, zxid);
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn outstanding_proposals_test() {
+    let (sender, receiver) = broadcast::channel(10);
+    let mut n = Node::new(0, sender, receiver, 4, &test_dir("outstanding_proposals_test"));
+    let (z1, z2, z3) = (make_zxid(1, 1), make_zxid(1, 2), make_zxid(1, 3));
+    n.outstanding.insert(z1, HashSet::from([0]));
+    n.outstanding.insert(z2, HashSet::from([0, 1, 2]));
+    n.outstanding.insert(z3, HashSet::from([0, 1]));
+    // z2 has its quorum but z1 doesn't yet, nothing can commit out of order
+    assert!(Node::take_committable(&mut n).is_empty());
+    n.outstanding.get_mut(&z1).unwrap().extend([1, 3]);
+    assert_eq!(Node::take_committable(&mut n), vec![z1, z2]);
+    assert_eq!(n.outstanding.keys().cloned().collect::<Vec<_>>(), vec![z3]);
+}
+
+#[tokio::test]
+async fn pipelined_writes() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("pipelined_writes"));
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    // all in flight at once, none waits for the one before it to commit
+    let paths: Vec<String> = (0..20).map(|i| format!("/p{}", i)).collect();
+    for result in join_all(paths.iter().map(|path| zk.create(path, ""))).await {
+        result.unwrap();
+    }
+    tokio::time::sleep(Duration::from_secs(8)).await;
+
+    let leader = zk.servers[&zk.latest_leader].lock().await;
+    assert!(leader.outstanding.is_empty());
+    let committed = leader.last_committed_zxid;
+    drop(leader);
+    for node in zk.servers.values() {
+        let n = node.lock().await;
+        assert_eq!(n.last_committed_zxid, committed);
+        assert_eq!(n.data.get_children("/").unwrap().len(), 20);
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[test]
+fn sync_mode_test() {
+    let (sender, receiver) = broadcast::channel(16);
+    let mut n = Node::new(0, sender, receiver, 3, &test_dir("sync_mode_test"));
+    n.last_snapshot = make_zxid(1, 2);
+    n.history = [make_zxid(1, 3), make_zxid(1, 4), make_zxid(2, 1)].into_iter().map(|zxid| {
+        let mut tx = Transaction::new();
+        tx.zxid = zxid;
+        tx
+    }).collect();
+
+    assert_eq!(Node::sync_mode(&n, make_zxid(1, 1)), SyncMode::Snap);
+    assert_eq!(Node::sync_mode(&n, make_zxid(1, 2)), SyncMode::Diff);
+    assert_eq!(Node::sync_mode(&n, make_zxid(1, 4)), SyncMode::Diff);
+    assert_eq!(Node::sync_mode(&n, make_zxid(2, 1)), SyncMode::Diff);
+    // proposals of a deposed leader that never made it into this history are cut off
+    assert_eq!(Node::sync_mode(&n, make_zxid(1, 5)), SyncMode::Trunc(make_zxid(1, 4)));
+    assert_eq!(Node::sync_mode(&n, make_zxid(2, 3)), SyncMode::Trunc(make_zxid(2, 1)));
+    // once the last zxid they share is no newer than the snapshot, the follower gets the whole state instead
+    n.history.retain(|tx| tx.zxid == make_zxid(2, 1));
+    assert_eq!(Node::sync_mode(&n, make_zxid(1, 3)), SyncMode::Snap);
+}
+
+#[tokio::test]
+async fn trunc_sync() {
+    // node 0 logged a proposal of epoch 1 nobody else has, while the rest moved on to epoch 2
+    let dir = test_dir("trunc_sync");
+    for id in 0..10 {
+        let (mut log, _, _) = TxnLog::open(&dir.join(format!("node_{}", id))).unwrap();
+        let epoch = if id == 0 { 1 } else { 2 };
+        log.write_epoch(ACCEPTED_EPOCH, epoch).unwrap();
+        log.write_epoch(CURRENT_EPOCH, epoch).unwrap();
+        if id == 0 {
+            let mut tx = Transaction::create_op("/stale", "");
+            tx.zxid = make_zxid(1, 5);
+            log.append(&tx).unwrap();
+        }
+    }
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    assert_ne!(zk.latest_leader, 0);
+    zk.create("/a", "").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    {
+        let n = zk.servers[&0].lock().await;
+        assert!(n.data.stat("/stale").is_none() && n.data.stat("/a").is_some());
+        assert!(n.history.iter().all(|tx| tx.zxid != make_zxid(1, 5)));
+    }
+    zk.quit().await;
+    join_all(handlers).await;
+
+    // and it's gone from its log as well
+    let (_, _, history) = TxnLog::open(&dir.join("node_0")).unwrap();
+    assert!(history.iter().all(|tx| tx.key != "/stale"));
+}
+
+#[test]
+fn state_transition_test() {
+    use NodeStatus::*;
+    use ZabPhase::*;
+    // out of the election into discovery, then forward one phase at a time
+    assert!(legal_transition((&Looking, Election), (&Following, Discovery)));
+    assert!(legal_transition((&Looking, Election), (&Leading, Discovery)));
+    assert!(legal_transition((&Looking, Election), (&Observing, Discovery)));
+    assert!(legal_transition((&Following, Discovery), (&Following, Synchronization)));
+    assert!(legal_transition((&Leading, Synchronization), (&Leading, Broadcast)));
+    // back to looking from anywhere, and quiting for good
+    assert!(legal_transition((&Leading, Broadcast), (&Looking, Election)));
+    assert!(legal_transition((&Observing, Synchronization), (&Quiting, Synchronization)));
+    assert!(!legal_transition((&Quiting, Broadcast), (&Looking, Election)));
+
+    assert!(!legal_transition((&Looking, Election), (&Following, Broadcast)));
+    assert!(!legal_transition((&Following, Discovery), (&Following, Broadcast)));
+    assert!(!legal_transition((&Following, Broadcast), (&Following, Discovery)));
+    assert!(!legal_transition((&Following, Synchronization), (&Leading, Broadcast)));
+    assert!(!legal_transition((&Leading, Discovery), (&Following, Discovery)));
+    assert!(!legal_transition((&Looking, Election), (&Looking, Discovery)));
+
+    // an illegal one leaves the node where it was
+    let (sender, receiver) = broadcast::channel(10);
+    let mut n = Node::new(0, sender, receiver, 3, &test_dir("state_transition_test"));
+    assert_eq!((n.status.clone(), n.phase), (Looking, Election));
+    assert!(!Node::set_state(&mut n, Following, Broadcast));
+    assert_eq!((n.status.clone(), n.phase), (Looking, Election));
+    assert!(Node::set_state(&mut n, Following, Discovery));
+    assert!(Node::set_state(&mut n, Following, Synchronization));
+    assert_eq!((n.status.clone(), n.phase), (Following, Synchronization));
+}
+
+#[tokio::test]
+async fn observer_test() {
+    let mut zk = Zookeeper::with_data_dir(5, &test_dir("observer_test"));
+    let observer = zk.add_observer();
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    assert!(zk.latest_leader < 5);
+    zk.create("/a", "x").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    {
+        let n = zk.servers[&observer].lock().await;
+        assert_eq!((n.status.clone(), n.phase), (NodeStatus::Observing, ZabPhase::Broadcast));
+        // it never voted
+        assert_eq!(n.election.round, 0);
+    }
+    // and its leader knows not to count it
+    assert!(zk.servers[&zk.latest_leader].lock().await.observers.contains(&observer));
+    assert_eq!(zk.get(observer, "/a").await.unwrap().unwrap().0, "x");
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn discovery_test() {
+    let dir = test_dir("discovery_test");
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+    // nobody serves clients before its leader tells it it's up to date
+    assert_eq!(zk.exists(0, "/").await, Err(ZkError::ConnectionLoss));
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/a", "").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    let epoch = zk.servers[&zk.latest_leader].lock().await.current_epoch;
+    assert!(epoch > 0);
+    for (id, node) in zk.servers.iter() {
+        {
+            let n = node.lock().await;
+            assert_eq!((n.accepted_epoch, n.current_epoch), (epoch, epoch));
+            assert_eq!(n.phase, ZabPhase::Broadcast);
+            assert!(n.status == NodeStatus::Leading || n.status == NodeStatus::Following);
+        }
+        assert!(zk.exists(*id, "/a").await.unwrap().is_some());
+    }
+    zk.quit().await;
+    join_all(handlers).await;
+
+    // the next leader has to pick an epoch above every one its followers promised
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    for node in zk.servers.values() {
+        let n = node.lock().await;
+        assert!(n.current_epoch > epoch);
+        assert!(n.data.stat("/a").is_some());
+    }
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn newleader_quorum() {
+    let mut zk = Zookeeper::with_data_dir(5, &test_dir("newleader_quorum"));
+    let handlers = zk.start_servers();
+
+    // catch the leader in discovery or sync, and take everyone else away before a quorum acks its NEWLEADER
+    let leader = 'found: loop {
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        for (id, node) in zk.servers.iter() {
+            let n = node.lock().await;
+            if n.status == NodeStatus::Leading {
+                assert_ne!(n.phase, ZabPhase::Broadcast);
+                break 'found *id;
+            }
+        }
+    };
+    for id in (1..5).map(|i| (leader + i) % 5) {
+        zk.stop_server(id).await;
+    }
+    assert_eq!(zk.exists(leader, "/").await, Err(ZkError::ConnectionLoss));
+    let proposed = zk.servers[&leader].lock().await.history.len();
+
+    // it never proposes the write, it gives up leading before the client gives up waiting or right after
+    assert_eq!(zk.write_to(leader, Transaction::create_op("/x", "")).await, Err(ZkError::ConnectionLoss));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    {
+        let n = zk.servers[&leader].lock().await;
+        assert_eq!(n.history.len(), proposed);
+        assert!(n.outstanding.is_empty() && n.data.stat("/x").is_none());
+    }
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn restart_replays_log() {
+    let dir = test_dir("restart_replays_log");
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    zk.create("/app", "v0").await.unwrap();
+    zk.set("/app", "v1", -1).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    zk.quit().await;
+    join_all(handlers).await;
+
+    // a brand new ensemble over the same logs comes back with the data before any election
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    for (id, node) in zk.servers.iter() {
+        let (data, stat) = node.lock().await.data.get_data("/app").unwrap();
+        assert_eq!(data, "v1");
+        assert_eq!(stat.version, 1);
+        // but it only serves it once it synced with a leader
+        assert_eq!(zk.get(*id, "/app").await, Err(ZkError::ConnectionLoss));
+    }
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    for id in zk.servers.keys() {
+        assert_eq!(zk.get(*id, "/app").await.unwrap().unwrap().0, "v1");
+    }
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn snapshots_and_restart() {
+    let dir = test_dir("snapshots_and_restart");
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    for node in zk.servers.values() {
+        node.lock().await.snap_count = 3;
+    }
+    let handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    for i in 0..7 {
+        zk.create(&format!("/n{}", i), "").await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    // any node that snapshotted only keeps the history after it, and its state survives a restart
+    let mut snapshotted = None;
+    for (id, node) in zk.servers.iter() {
+        let n = node.lock().await;
+        if n.last_snapshot > 0 {
+            assert!(n.history.iter().all(|tx| tx.zxid > n.last_snapshot));
+            snapshotted = Some((*id, n.data.get_children("/").unwrap()));
+        }
+    }
+    let (id, children) = snapshotted.unwrap();
+    zk.quit().await;
+    join_all(handlers).await;
+    assert!(std::fs::read_dir(dir.join(format!("node_{}", id))).unwrap()
+        .any(|e| e.unwrap().file_name().to_string_lossy().starts_with("snapshot.")));
+
+    // comes back from the newest snapshot plus the log after it
+    let mut zk = Zookeeper::with_data_dir(10, &dir);
+    let handlers = zk.start_servers();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let restored = zk.servers[&id].lock().await.data.get_children("/").unwrap();
+    assert!(children.iter().all(|child| restored.contains(child)));
+    assert_eq!(zk.get_children(id, "/").await, Err(ZkError::ConnectionLoss));
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    let served = zk.get_children(id, "/").await.unwrap();
+    assert!(children.iter().all(|child| served.contains(child)));
+    zk.quit().await;
+    join_all(handlers).await;
+}
+
+#[tokio::test]
+async fn restart_single_server() {
+    let mut zk = Zookeeper::with_data_dir(10, &test_dir("restart_single_server"));
+    let mut handlers = zk.start_servers();
+
+    tokio::time::sleep(Duration::from_secs(20)).await;
+    zk.find_latest_leader().await;
+    let follower = (zk.latest_leader + 1) % 10;
+    zk.create("/before", "").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+    let epochs = {
+        let n = zk.servers[&follower].lock().await;
+        (n.accepted_epoch, n.current_epoch)
+    };
+    assert!(epochs.1 > 0);
+
+    zk.stop_server(follower).await;
+    assert_eq!(zk.servers[&follower].lock().await.status, NodeStatus::Quiting);
+    zk.create("/while_down", "").await.unwrap();
+    tokio::time::sleep(Duration::from_secs(8)).await;
+    assert_eq!(zk.exists(follower, "/while_down").await, Err(ZkError::ConnectionLoss));
+
+    // it comes back with what it had logged, and catches up on the rest from the leader
+    handlers.push(zk.restart_server(follower).await);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    {
+        // its epochs were on disk, it can't go back to an older one
+        let n = zk.servers[&follower].lock().await;
+        assert!(n.data.stat("/before").is_some());
+        assert!(n.accepted_epoch >= epochs.0 && n.current_epoch >= epochs.1);
+    }
+    // it has /before, but clients only get it once the leader says it's up to date
+    assert_eq!(zk.exists(follower, "/before").await, Err(ZkError::ConnectionLoss));
+    tokio::time::sleep(Duration::from_secs(15)).await;
+    assert!(zk.exists(follower, "/while_down").await.unwrap().is_some());
+    assert_eq!(zk.servers[&follower].lock().await.leader_id, Some(zk.latest_leader));
+
+    zk.quit().await;
+    join_all(handlers).await;
+}
@@ -1,35 +1,111 @@
+use crate::data_tree::{CreateMode, DataTree, PendingChanges, ZkError};
+use crate::election::{is_quorum, ElectionAlg, FastLeaderElection, Vote};
+use crate::session::{SessionTracker, TICK_MS};
+use crate::snapshot::Snapshot;
+use crate::txn_log::{TxnLog, ACCEPTED_EPOCH, CURRENT_EPOCH, SNAP_COUNT, SNAP_RETAIN_COUNT};
+use crate::watch::WatchManager;
 use rand::Rng;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::{self, Write};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{broadcast, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, Mutex};
 use tokio::task;
-use tokio::time::{sleep, Instant};
+use tokio::time::{sleep, sleep_until, Instant};
 
 
+// where a client waits for the result of its write or sync, the zxid it committed or caught up to, or why it didn't
+pub type Reply = mpsc::UnboundedSender<Result<i64, ZkError>>;
+
+// how long after a heartbeat a quorum acked the leader still holds its lease. Each of them waits 5s without a
+// heartbeat before it goes looking, and no new leader is elected without one of them, so this leaves a margin
+pub const LEASE: Duration = Duration::from_secs(4);
+
+// client writes a server holds on to while it has no leader to take them, any more are turned away right away
+const MAX_QUEUED_WRITES: usize = 1000;
+
+// ZooKeeper's peer states, a node is looking until the election settles and then follows, leads or observes
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum NodeStatus {
-    //Leaderless, following represent following + alive_leader flag
+    Looking,
     Following,
     Leading,
-    Running,
+    Observing, // learns the committed history without voting, see Node::observer
     Quiting,
-    // Sync, Discovery, Broadcast
+}
+
+// The phases of Zab, a looking node is electing and the others go through the rest in order
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ZabPhase {
+    Election,
+    Discovery, // FOLLOWERINFO, NEWEPOCH and ACKEPOCH settle the new epoch
+    Synchronization, // DIFF, TRUNC or SNAP, then NEWLEADER
+    Broadcast, // UPTODATE was sent or received, proposals flow and clients are served
+}
+
+// A node goes back to looking from anywhere, leaves it to follow, lead or observe from the start of discovery,
+// and only moves forward through the phases after that. Quiting is final
+pub fn legal_transition(from: (&NodeStatus, ZabPhase), to: (&NodeStatus, ZabPhase)) -> bool {
+    use NodeStatus::*;
+    use ZabPhase::*;
+    match (from, to) {
+        ((Quiting, _), _) => false,
+        (_, (Quiting, _)) => true,
+        (_, (Looking, Election)) => true,
+        ((Looking, Election), (Following | Leading | Observing, Discovery)) => true,
+        ((status, Discovery), (next, Synchronization)) | ((status, Synchronization), (next, Broadcast)) => status == next && *status != Looking,
+        _ => false,
+    }
 }
 
 pub(crate) struct Node {
     pub id: i32,
     pub status: NodeStatus,
+    pub phase: ZabPhase, // only moves through set_state, together with status
+    pub observer: bool, // never votes or leads, only learns the history, like a ZooKeeper observer
     pub sender: broadcast::Sender<Message>,
     pub receiver: broadcast::Receiver<Message>,
+    outbox: mpsc::UnboundedSender<(Instant, Message)>, // messages on their way, with when they arrive
+    outbox_receiver: Option<mpsc::UnboundedReceiver<(Instant, Message)>>, // handed to the delivery task by run
     pub leader_id: core::option::Option<i32>,
-    pub epoch: i32,
+    pub leader_rank: (i32, i64, i32), // epoch, last zxid and id its leader's heartbeats last carried
+    pub accepted_epoch: i32, // promised to the leader it follows, leaders of older epochs are ignored
+    pub current_epoch: i32, // epoch of the last leader it synced with, what elections compare
+    pub new_epoch: i32, // the epoch it's establishing while leading, one above what every connecting follower accepted
+    pub connecting_followers: HashSet<i32>, // sent their accepted epoch for the new one
+    pub electing_followers: HashMap<i32, i64>, // acked the new epoch, with the last zxid each of them logged
+    pub newleader_acks: HashSet<i32>, // acked NEWLEADER, the leader starts broadcasting once a quorum did
+    pub freshest: Option<(i32, i32, i64)>, // id, current epoch and last zxid of the follower with the most up to date history acked so far, if it's newer than the leader's own
+    pub observers: HashSet<i32>, // learners that told it they're observing, they never count toward a quorum
     pub history: Vec<Transaction>, //aka history
     pub cluster_size: i32,
     pub last_heartbeat: Instant,
-    pub supporting_nodes: HashSet<i32>,
-    // don't need a map to represent data, coz, technically, having
+    pub synced: bool, // got its leader's history, until then only the leader's discovery and sync messages keep it following
+    pub supporting_nodes: HashSet<i32>, // approved its campaign
+    pub pending_writes: HashMap<i64, Reply>, // clients waiting for the tx of that zxid to be applied here
+    pub forwarded: HashMap<i64, Reply>, // clients whose write or sync went on to the leader, by request id, until it answers
+    pub pending_syncs: Vec<(i64, Reply)>, // clients waiting for it to commit up to that zxid, what the leader had committed when they synced
+    pub queued_writes: Vec<(Transaction, Reply)>, // client writes that came in while it had no leader to take them
+    next_request_id: i64,
+    pub outstanding: BTreeMap<i64, HashSet<i32>>, // leader only, proposals not committed yet by zxid, with who acked each, like ZooKeeper's outstandingProposals
+    pending_changes: PendingChanges, // leader only, what its proposals not applied yet change, new writes are checked against it
+    heartbeat_round: i64, // leader only, counts the heartbeats it sent
+    heartbeat_acks: BTreeMap<i64, (Instant, HashSet<i32>)>, // leader only, recent heartbeats by round, when each was sent and who acked it
+    pub lease_until: Option<Instant>, // leader only, see LEASE
+    pub election_alg: ElectionAlg,
+    pub election: FastLeaderElection, // its votes and what it heard, only used by ElectionAlg::Fast
+    pub election_started: Option<Instant>, // since when it's been without a leader, for comparing how fast elections settle
+    pub data: DataTree, // state machine built from committed history
+    pub last_committed_zxid: i64, // history up to it reached a quorum, what's after it is only proposed
+    pub last_applied: i64, // zxid of the last tx executed against data, it catches up with last_committed_zxid right away
+    pub sessions: SessionTracker, // follows committed sessions, only the leader expires them
+    pub watches: WatchManager, // set by clients reading from this node
+    pub log: TxnLog, // on disk copy of history, a tx is logged before it's proposed or acked
+    pub last_snapshot: i64, // zxid of the latest snapshot, history only holds the txs after it
+    pub snap_count: i32, // txs applied between two snapshots
+    pub txs_since_snapshot: i32,
 }
 
 
@@ -38,32 +114,127 @@ pub enum TxActions {
     NA,
     Del,
     New,
+    Set,
+    Check, // only valid inside a Multi, asserts a znode's version
+    Multi, // ops committed together under one zxid
+    CreateSession,
+    CloseSession, // also deletes the session's ephemerals
 }
 
 #[derive(Clone, Debug)]
 pub struct Transaction {
-    pub zxid: i32,
-    pub action: TxActions, // delete/ create
-    pub key: String,
+    pub zxid: i64, // epoch of the leader that proposed it in the high 32 bits, see make_zxid
+    pub action: TxActions, // delete/ create/ set
+    pub key: String, // znode path
     pub val: String,
+    pub version: i32, // expected znode version for set/ delete/ check, -1 matches any
+    pub ops: Vec<Transaction>, // only used by Multi
+    pub mode: CreateMode, // only used by New
+    pub session_id: i64, // owner of an ephemeral, or the session being opened/ closed
+    pub timeout: i32, // requested, then negotiated session timeout in ms, only used by CreateSession
+    pub time: i64, // ms since unix epoch, stamped by the leader so every replica agrees
 }
 
 impl Transaction {
     pub fn new() -> Self {
-        Transaction { zxid: -1, action: TxActions::NA, key: String::new(), val: String::new() }
+        Transaction { zxid: -1, action: TxActions::NA, key: String::new(), val: String::new(), version: -1, ops: Vec::new(), mode: CreateMode::Persistent, session_id: 0, timeout: 0, time: 0 }
+    }
+
+    pub fn create_op(path: &str, data: &str) -> Self {
+        Transaction::create_mode_op(path, data, CreateMode::Persistent, 0)
+    }
+
+    pub fn create_mode_op(path: &str, data: &str, mode: CreateMode, session_id: i64) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = TxActions::New;
+        tx.key = path.to_string();
+        tx.val = data.to_string();
+        tx.mode = mode;
+        tx.session_id = session_id;
+        tx
+    }
+
+    pub fn set_op(path: &str, data: &str, expected_version: i32) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = TxActions::Set;
+        tx.key = path.to_string();
+        tx.val = data.to_string();
+        tx.version = expected_version;
+        tx
+    }
+
+    pub fn delete_op(path: &str, expected_version: i32) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = TxActions::Del;
+        tx.key = path.to_string();
+        tx.version = expected_version;
+        tx
+    }
+
+    pub fn check_op(path: &str, expected_version: i32) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = TxActions::Check;
+        tx.key = path.to_string();
+        tx.version = expected_version;
+        tx
+    }
+
+    pub fn session_op(action: TxActions, session_id: i64) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = action;
+        tx.session_id = session_id;
+        tx
+    }
+
+    pub fn multi(ops: Vec<Transaction>) -> Self {
+        let mut tx = Transaction::new();
+        tx.action = TxActions::Multi;
+        tx.ops = ops;
+        tx
     }
 }
 
+// Same layout as ZooKeeper's zxid: the epoch in the high 32 bits and a counter that restarts with
+// every epoch in the low 32, so comparing zxids orders by epoch first and a new leader can't
+// reissue a zxid a deposed one already handed out
+pub fn make_zxid(epoch: i32, counter: i64) -> i64 {
+    ((epoch as i64) << 32) | counter
+}
+
+pub fn zxid_epoch(zxid: i64) -> i32 {
+    (zxid >> 32) as i32
+}
+
+fn zxid_counter(zxid: i64) -> i64 {
+    zxid & 0xffff_ffff
+}
+
+
+// how a leader brings a follower up to date, see sync_mode
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SyncMode {
+    Diff,
+    Trunc(i64), // to the last zxid both logs share, then a Diff
+    Snap,
+}
 
 #[derive(Clone, Debug)]
 pub struct Message {
     pub msg_type: MessageType,
     pub sender_id: i32,
-    last_zxid: i32,
+    last_zxid: i64,
     epoch: i32,
+    current_epoch: i32, // only used by AckEpoch
+    round: i64, // only used by Notification, the election round of the vote, and Heartbeat and AckHeartbeat, the heartbeat's
+    voted_for: i32, // only used by Notification, its epoch and last zxid are in epoch and last_zxid
+    state: NodeStatus, // only used by Notification, whether the sender is still looking, and FollowerInfo, whether it observes
     pub receiver_id: i32, // only used in voting, msg is broadcast by default
     pub tx: Transaction,
     history: Vec<Transaction>,
+    snapshot: Option<Arc<Snapshot>>, // only used by Snap and History, shared as every receiver gets a clone
+    pub reply: Option<Reply>, // only used by a client's Write and Sync, where its result goes
+    origin: Option<(i32, i64)>, // only used by Request, Sync, Reply and Write, the server a client's write or sync came in through and its request id there
+    error: Option<ZkError>, // only used by Reply
 }
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub(crate) enum MessageType {
@@ -72,20 +243,35 @@ pub(crate) enum MessageType {
     Running,
     Approve,
     Reject,
-    Recovery,
-    SyncRequest,
-    Sync,
+    Notification, // a vote of the fast leader election
+    FollowerInfo, // a follower's accepted epoch and last zxid, the first step of discovery
+    NewEpoch, // the epoch the leader is establishing
+    AckEpoch, // a follower promised the new epoch, with its current epoch and last zxid
+    GetHistory, // the new leader is behind the freshest follower that acked, it asks that one alone for its history
+    History, // answers GetHistory with the follower's applied state and the proposals after it
+    Diff, // the proposals a follower is missing, after the zxid its log ends at
+    Trunc, // a follower has to drop the proposals it logged after last_zxid, a Diff follows
+    Snap, // a follower too far behind gets the leader's whole state
+    NewLeader, // ends a sync, the follower now holds the leader's history in its epoch
+    AckNewLeader,
+    UpToDate, // a quorum acked NEWLEADER, the leader broadcasts and its followers serve clients. Carries how far it committed
     Write,
+    Request, // a client's write a learner passes on to its leader
+    Sync, // a client or a learner asking how far the leader committed
+    Reply, // answers a Request the leader couldn't propose with the error, or a Sync with its commit point in last_zxid
+    Ping, // client keeping its session (tx.session_id) alive
     AckTX,
     Commit,
+    AckHeartbeat, // keeps the leader's lease, see LEASE
     Quit,
 }
 
 impl Message {
     pub fn new(sender_id: i32, msg_type: MessageType) -> Self {
-        Message { sender_id, msg_type, last_zxid: 0, epoch: 0, receiver_id: -1, tx: Transaction::new(), history: Vec::new() } // -1 stands for broadcast
+        Message { sender_id, msg_type, last_zxid: 0, epoch: 0, current_epoch: 0, round: 0, voted_for: 0, state: NodeStatus::Looking, receiver_id: -1, tx: Transaction::new(), history: Vec::new(), snapshot: None, reply: None, origin: None, error: None } // -1 stands for broadcast
     }
 
+    #[allow(dead_code)] // handy when debugging the protocol
     pub fn message_report(&self) {
         println!("from {}, to {}, {:?}", self.sender_id, self.receiver_id, self.msg_type);
     }
@@ -93,90 +279,602 @@ impl Message {
 
 
 impl Node {
-    pub fn new(id: i32, sender: broadcast::Sender<Message>, receiver: broadcast::Receiver<Message>, cluster_size: i32) -> Node {
-        Node {
+    // picks up whatever history a previous run logged under data_dir
+    pub fn new(id: i32, sender: broadcast::Sender<Message>, receiver: broadcast::Receiver<Message>, cluster_size: i32, data_dir: &Path) -> Node {
+        let (log, snapshot, history) = TxnLog::open(&data_dir.join(format!("node_{}", id))).expect("failed to open the transaction log");
+        // the epoch files may not have been written yet, the log still tells which epoch it got to
+        let logged_zxid = history.last().map_or(snapshot.as_ref().map_or(0, |s| s.zxid), |tx| tx.zxid);
+        let current_epoch = log.read_epoch(CURRENT_EPOCH).expect("failed to read the current epoch").unwrap_or(0).max(zxid_epoch(logged_zxid));
+        let accepted_epoch = log.read_epoch(ACCEPTED_EPOCH).expect("failed to read the accepted epoch").unwrap_or(0).max(current_epoch);
+        let (outbox, outbox_receiver) = mpsc::unbounded_channel();
+        let mut node = Node {
             id,
             leader_id: None,
-            status: NodeStatus::Following,
+            leader_rank: (0, 0, 0),
+            status: NodeStatus::Looking,
+            phase: ZabPhase::Election,
+            observer: false,
             sender,
             receiver,
-            epoch: 0,
-            history: Vec::new(),
+            outbox,
+            outbox_receiver: Some(outbox_receiver),
+            accepted_epoch,
+            current_epoch,
+            new_epoch: 0,
+            connecting_followers: HashSet::new(),
+            electing_followers: HashMap::new(),
+            newleader_acks: HashSet::new(),
+            freshest: None,
+            observers: HashSet::new(),
+            history,
             cluster_size,
             last_heartbeat: Instant::now(),
+            synced: false,
             supporting_nodes: HashSet::new(),
+            pending_writes: HashMap::new(),
+            forwarded: HashMap::new(),
+            pending_syncs: Vec::new(),
+            queued_writes: Vec::new(),
+            next_request_id: 0,
+            outstanding: BTreeMap::new(),
+            pending_changes: PendingChanges::new(),
+            heartbeat_round: 0,
+            heartbeat_acks: BTreeMap::new(),
+            lease_until: None,
+            election_alg: ElectionAlg::Fast,
+            election: FastLeaderElection::new(cluster_size),
+            election_started: None,
+            data: DataTree::new(),
+            last_committed_zxid: log.read_last_committed().expect("failed to read the last committed zxid"),
+            last_applied: 0,
+            sessions: SessionTracker::new(),
+            watches: WatchManager::new(),
+            log,
+            last_snapshot: 0,
+            snap_count: SNAP_COUNT,
+            txs_since_snapshot: 0,
+        };
+        if let Some(snapshot) = snapshot {
+            Node::install_snapshot(&mut node, &snapshot);
         }
+        node
     }
 
 
+    // the network delay is simulated by the delivery task, so a node doesn't sit on its lock while a message travels
     async fn broadcast(node: &Node, msg: Message) {
         let random_delay = rand::thread_rng().gen_range(200..800);
-        sleep(Duration::from_millis(random_delay)).await;
+        node.outbox.send((Instant::now() + Duration::from_millis(random_delay), msg)).unwrap();
+    }
 
-        node.sender.send(msg).unwrap();
+    // every message arrives after its own delay but never overtakes one sent before it, like over a tcp connection.
+    // Ends once the node is dropped
+    async fn deliver(mut outbox: mpsc::UnboundedReceiver<(Instant, Message)>, sender: broadcast::Sender<Message>) {
+        while let Some((arrival, msg)) = outbox.recv().await {
+            sleep_until(arrival).await;
+            let _ = sender.send(msg);
+        }
     }
 
-    fn last_zxid(n: &Node) -> i32 {
+    fn last_zxid(n: &Node) -> i64 {
         let len = n.history.len();
 
         if len == 0 {
-            return 0;
+            // everything before the last snapshot was applied and dropped from history
+            return n.last_applied;
         }
 
         n.history[len - 1].zxid
     }
 
 
-    async fn follow_and_discovery(n: &mut Node, msg: &Message) {
-        // This is where discovery begin, establish new connection
-        n.leader_id = Some(msg.sender_id);
+    // both epochs only ever move forward, and hit the disk before the node acts on them
+    fn set_accepted_epoch(n: &mut Node, epoch: i32) -> io::Result<()> {
+        n.log.write_epoch(ACCEPTED_EPOCH, epoch)?;
+        n.accepted_epoch = epoch;
+        Ok(())
+    }
+
+    fn set_current_epoch(n: &mut Node, epoch: i32) -> io::Result<()> {
+        n.log.write_epoch(CURRENT_EPOCH, epoch)?;
+        n.current_epoch = epoch;
+        Ok(())
+    }
+
+    // every change of status or phase goes through here, an illegal one is refused and leaves the node as it was
+    pub fn set_state(n: &mut Node, status: NodeStatus, phase: ZabPhase) -> bool {
+        if !legal_transition((&n.status, n.phase), (&status, phase)) {
+            println!("{} can't go from {:?} in {:?} to {:?} in {:?}", n.id, n.status, n.phase, status, phase);
+            return false;
+        }
+        n.status = status;
+        n.phase = phase;
+        true
+    }
+
+    // back to looking without a leader or anything synced from one
+    fn looking(n: &mut Node) -> bool {
+        if !Node::set_state(n, NodeStatus::Looking, ZabPhase::Election) {
+            return false;
+        }
+        n.leader_id = None;
         n.last_heartbeat = Instant::now();
-        n.status = NodeStatus::Following;
-        n.epoch = msg.epoch;
-        // Broadcast all it's history
-        for tx in n.history.iter() {
-            let mut history = Message::new(n.id, MessageType::Recovery);
-            history.receiver_id = n.leader_id.unwrap();
-            history.tx = tx.clone();
-            Node::broadcast(&n, history).await;
+        n.synced = false;
+        n.supporting_nodes = HashSet::new();
+        n.election_started.get_or_insert(Instant::now());
+        // whatever it was waiting to commit may or may not make it under the next leader,
+        // and what it held on to for the last one is failed too, the client may retry it by the time a leader takes it
+        for (_, reply) in n.pending_writes.drain().chain(n.forwarded.drain()).chain(n.pending_syncs.drain(..)) {
+            let _ = reply.send(Err(ZkError::ConnectionLoss));
+        }
+        for (_, reply) in n.queued_writes.drain(..) {
+            let _ = reply.send(Err(ZkError::ConnectionLoss));
         }
-        sleep(Duration::from_secs(2)).await;
+        true
+    }
 
-        let mut request_sync = Message::new(n.id, MessageType::SyncRequest);
-        request_sync.receiver_id = msg.sender_id;
-        Node::broadcast(&n, request_sync).await;
-        // request a sync from leader
+    async fn follow_and_discovery(n: &mut Node, leader_id: i32, leader_rank: Vote) {
+        // This is where discovery begin, establish new connection
+        let status = if n.observer { NodeStatus::Observing } else { NodeStatus::Following };
+        if !Node::set_state(n, status, ZabPhase::Discovery) {
+            return;
+        }
+        Node::election_over(n, leader_id);
+        n.leader_id = Some(leader_id);
+        n.leader_rank = leader_rank;
+        // what it answers nodes that are still looking
+        n.election.vote = n.leader_rank;
+        n.last_heartbeat = Instant::now();
+        n.synced = false;
+        Node::send_follower_info(n).await;
+    }
 
+    // the leader answers with the epoch it's establishing, once it heard from a quorum
+    async fn send_follower_info(n: &Node) {
+        let Some(leader_id) = n.leader_id else { return };
+        let mut info = Message::new(n.id, MessageType::FollowerInfo);
+        info.receiver_id = leader_id;
+        info.epoch = n.accepted_epoch;
+        info.last_zxid = Node::last_zxid(n);
+        info.state = n.status.clone();
+        Node::broadcast(n, info).await;
     }
 
-    async fn give_up_election(n: &mut Node) {
-        println!("{} give up election", n.id);
-        // Node::node_report(n);
-        // msg.message_report();
+    fn has_quorum(n: &Node, count: usize) -> bool {
+        is_quorum(n.cluster_size, count)
+    }
 
-        n.status = NodeStatus::Following;
+    fn election_over(n: &mut Node, leader_id: i32) {
+        if let Some(started) = n.election_started.take() {
+            println!("{} settled on leader {} after {:?} with {:?}", n.id, leader_id, started.elapsed(), n.election_alg);
+        }
+    }
+
+    // it only serves once its followers went through discovery and sync, see FollowerInfo
+    fn start_leading(n: &mut Node) {
+        if !Node::set_state(n, NodeStatus::Leading, ZabPhase::Discovery) {
+            return;
+        }
+        Node::election_over(n, n.id);
         n.leader_id = None;
         n.last_heartbeat = Instant::now();
         n.supporting_nodes = HashSet::new();
+        n.election.vote = (n.current_epoch, Node::last_zxid(n), n.id);
+        n.new_epoch = n.accepted_epoch + 1;
+        n.connecting_followers = HashSet::from([n.id]);
+        n.electing_followers = HashMap::from([(n.id, Node::last_zxid(n))]);
+        n.newleader_acks = HashSet::new();
+        n.freshest = None;
+        n.observers = HashSet::new();
+        n.outstanding = BTreeMap::new();
+        n.pending_changes = PendingChanges::new();
+        n.heartbeat_acks = BTreeMap::new();
+        n.lease_until = None;
     }
 
-    pub async fn execute_tx(n: &mut Node) {}
+    // the fast leader election settled. A follower starts discovery right away, its FOLLOWERINFO goes out again
+    // with every heartbeat in case it got there before the leader settled as well
+    async fn elected(n: &mut Node, vote: Vote) {
+        println!("{} elected {} in round {}", n.id, vote.2, n.election.round);
+        if vote.2 == n.id {
+            Node::start_leading(n);
+            return;
+        }
+        Node::follow_and_discovery(n, vote.2, vote).await;
+    }
 
-    pub fn node_report(n: &Node) {
-        let mut report;
-        if n.leader_id == None {
-            report = format!("id: {}, status: {:?}, leader: None epoch {} ", n.id, n.status, n.epoch);
+    fn notification(n: &Node) -> Message {
+        let mut notification = Message::new(n.id, MessageType::Notification);
+        (notification.epoch, notification.last_zxid, notification.voted_for) = n.election.vote;
+        notification.round = n.election.round;
+        notification.state = n.status.clone();
+        notification
+    }
+
+    // following or observing the leader msg came from
+    fn learns_from(n: &Node, leader_id: i32) -> bool {
+        (n.status == NodeStatus::Following || n.status == NodeStatus::Observing) && n.leader_id == Some(leader_id)
+    }
+
+    // done with discovery, see the AckEpoch answer
+    fn is_established(n: &Node) -> bool {
+        n.status == NodeStatus::Leading && (n.phase == ZabPhase::Synchronization || n.phase == ZabPhase::Broadcast)
+    }
+
+    // done with sync as well and taking writes, see the AckNewLeader answer
+    fn is_broadcasting(n: &Node) -> bool {
+        n.status == NodeStatus::Leading && n.phase == ZabPhase::Broadcast
+    }
+
+    // clients are only answered by a leader that's broadcasting or a learner its leader told it's up to date
+    pub fn is_serving(n: &Node) -> bool {
+        n.phase == ZabPhase::Broadcast && matches!(n.status, NodeStatus::Leading | NodeStatus::Following | NodeStatus::Observing)
+    }
+
+    // a broadcasting leader that heard back from a quorum recently enough that no other leader can have been elected,
+    // so its committed state is the latest and it can serve reads on its own
+    pub fn has_lease(n: &Node) -> bool {
+        Node::is_broadcasting(n) && n.lease_until.is_some_and(|until| Instant::now() < until)
+    }
+
+    // the applied state as a snapshot plus whatever is still being proposed on top of it
+    fn attach_state(n: &Node, msg: &mut Message) {
+        msg.snapshot = Some(Arc::new(Node::snapshot(n)));
+        msg.history = n.history.iter().filter(|tx| tx.zxid > n.last_applied).cloned().collect();
+    }
+
+    // Like ZooKeeper's LearnerHandler: a follower whose log ends at a zxid this node holds only misses what came after it,
+    // one that logged proposals this node doesn't have cuts back to the last zxid they share,
+    // and one that's behind the latest snapshot needs the whole state, the history no longer goes back that far
+    pub fn sync_mode(n: &Node, follower_zxid: i64) -> SyncMode {
+        if follower_zxid < n.last_snapshot {
+            return SyncMode::Snap;
+        }
+        if follower_zxid == n.last_snapshot || n.history.iter().any(|tx| tx.zxid == follower_zxid) {
+            return SyncMode::Diff;
+        }
+        // a truncation point at or below the latest snapshot may be older than a snapshot the follower took itself,
+        // which it would reload from, divergence and all. It's only told to start over from this node's state then
+        match n.history.iter().rev().map(|tx| tx.zxid).find(|zxid| *zxid < follower_zxid) {
+            Some(shared) => SyncMode::Trunc(shared),
+            None => SyncMode::Snap,
+        }
+    }
+
+    async fn sync_follower(n: &Node, follower_id: i32, follower_zxid: i64) {
+        let mode = Node::sync_mode(n, follower_zxid);
+        println!("{} syncs {} from zxid {:#x} with {:?}", n.id, follower_id, follower_zxid, mode);
+        let mut from = follower_zxid;
+        if let SyncMode::Trunc(zxid) = mode {
+            let mut trunc = Message::new(n.id, MessageType::Trunc);
+            trunc.receiver_id = follower_id;
+            trunc.epoch = n.current_epoch;
+            trunc.last_zxid = zxid;
+            Node::broadcast(n, trunc).await;
+            from = zxid;
+        }
+
+        let mut sync_msg = Message::new(n.id, if mode == SyncMode::Snap { MessageType::Snap } else { MessageType::Diff });
+        sync_msg.receiver_id = follower_id;
+        sync_msg.epoch = n.current_epoch;
+        if mode == SyncMode::Snap {
+            Node::attach_state(n, &mut sync_msg);
+        } else {
+            sync_msg.last_zxid = from;
+            sync_msg.history = n.history.iter().filter(|tx| tx.zxid > from).cloned().collect();
+        }
+        Node::broadcast(n, sync_msg).await;
+
+        let mut new_leader = Message::new(n.id, MessageType::NewLeader);
+        new_leader.receiver_id = follower_id;
+        new_leader.epoch = n.current_epoch;
+        Node::broadcast(n, new_leader).await;
+    }
+
+    // drops what was logged after zxid and reloads from what's left on disk, like ZooKeeper's truncateLog
+    async fn truncate(n: &mut Node, zxid: i64) -> io::Result<()> {
+        n.log.truncate(zxid)?;
+        let (log, snapshot, history) = TxnLog::open(n.log.dir())?;
+        n.log = log;
+        n.history = history;
+        let snapshot = snapshot.unwrap_or(Snapshot { zxid: 0, data: DataTree::new(), sessions: Vec::new() });
+        Node::rebuild_data(n, &snapshot).await;
+        Ok(())
+    }
+
+    // takes over another node's state and history wholesale
+    async fn install_history(n: &mut Node, snapshot: &Snapshot, history: Vec<Transaction>) -> io::Result<()> {
+        // the snapshot has to be on disk before the log stops covering what it holds
+        TxnLog::save_snapshot(n.log.dir(), snapshot)?;
+        n.history = history;
+        Node::rebuild_data(n, snapshot).await;
+        Node::rewrite_log(n)
+    }
+
+    // a quorum acked the new epoch and the leader holds the freshest history among them, it syncs them to it
+    async fn establish_epoch(n: &mut Node) {
+        let epoch = n.new_epoch;
+        if let Err(e) = Node::set_current_epoch(n, epoch) {
+            println!("{} failed to move to epoch {}: {}", n.id, epoch, e);
+            return;
+        }
+        if !Node::set_state(n, NodeStatus::Leading, ZabPhase::Synchronization) { return; }
+        println!("{} established epoch {}", n.id, epoch);
+        n.newleader_acks = HashSet::from([n.id]);
+        let followers: Vec<(i32, i64)> = n.electing_followers.iter().filter(|(id, _)| **id != n.id).map(|(id, zxid)| (*id, *zxid)).collect();
+        for (follower_id, follower_zxid) in followers {
+            Node::sync_follower(n, follower_id, follower_zxid).await;
+        }
+    }
+
+    // a looking voter starts a new round of the fast leader election right away,
+    // otherwise it waits for a better leader's heartbeat or its own timeout
+    async fn give_up_election(n: &mut Node) {
+        println!("{} give up election", n.id);
+        if n.election_alg == ElectionAlg::Fast && !n.observer {
+            Node::starts_running(n).await;
+        } else {
+            Node::looking(n);
+        }
+    }
+
+    // commit_zxid and everything before it is committed, apply what wasn't yet.
+    // The only way data moves forward, so readers never see a proposal that could still be rolled back
+    pub async fn execute_tx(n: &mut Node, commit_zxid: i64) {
+        if commit_zxid > n.last_committed_zxid {
+            n.last_committed_zxid = commit_zxid;
+            if let Err(e) = n.log.write_last_committed(commit_zxid) {
+                println!("{} failed to record zxid {:#x} as committed: {}", n.id, commit_zxid, e);
+            }
+        }
+        // history is sorted by zxid, apply everything committed but not applied yet
+        for i in 0..n.history.len() {
+            let tx = n.history[i].clone();
+            if tx.zxid <= n.last_applied { continue; }
+            if tx.zxid > n.last_committed_zxid { break; }
+
+            // a failed tx fails the same way on every replica, so it still counts as applied
+            let result = n.data.process_tx(&tx);
+            match &result {
+                Err(e) => println!("{} failed to execute zxid {} on {}: {:?}", n.id, tx.zxid, tx.key, e),
+                Ok(()) => match tx.action {
+                    TxActions::CreateSession => n.sessions.add(tx.session_id, tx.timeout),
+                    TxActions::CloseSession => n.sessions.remove(tx.session_id),
+                    _ => {}
+                },
+            }
+            // the client hears back once its write is applied, so its next read here sees it
+            if let Some(reply) = n.pending_writes.remove(&tx.zxid) {
+                let _ = reply.send(result.map(|_| tx.zxid));
+            }
+            for (event_type, path) in n.data.take_changes() {
+                n.watches.trigger(event_type, &path);
+            }
+            if let TxActions::CloseSession = tx.action {
+                n.watches.remove_session(tx.session_id);
+            }
+            n.last_applied = tx.zxid;
+            n.txs_since_snapshot += 1;
+        }
+        n.pending_changes.applied(n.last_applied);
+        Node::resolve_syncs(n);
+        if n.txs_since_snapshot >= n.snap_count {
+            Node::take_snapshot(n);
+        }
+    }
+
+    // answers the syncs whose commit point it applied by now
+    fn resolve_syncs(n: &mut Node) {
+        let committed = n.last_committed_zxid;
+        let (done, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut n.pending_syncs).into_iter().partition(|(zxid, _)| *zxid <= committed);
+        n.pending_syncs = waiting;
+        for (_, reply) in done {
+            let _ = reply.send(Ok(committed));
+        }
+    }
+
+    fn snapshot(n: &Node) -> Snapshot {
+        Snapshot { zxid: n.last_applied, data: n.data.clone(), sessions: n.sessions.timeouts() }
+    }
+
+    // Fuzzy like ZooKeeper's: the tree is copied under the lock, but written out in the background
+    // while txs keep being applied. Until it's on disk the older snapshot and the log still cover it
+    fn take_snapshot(n: &mut Node) {
+        let snapshot = Node::snapshot(n);
+        n.last_snapshot = snapshot.zxid;
+        n.txs_since_snapshot = 0;
+        n.history.retain(|tx| tx.zxid > snapshot.zxid);
+        let next_zxid = Node::last_zxid(n) + 1;
+        if let Err(e) = n.log.roll(next_zxid) {
+            println!("{} failed to start a new log segment: {}", n.id, e);
+        }
+
+        let (id, dir) = (n.id, n.log.dir().to_path_buf());
+        task::spawn_blocking(move || {
+            match TxnLog::save_snapshot(&dir, &snapshot).and_then(|_| TxnLog::purge(&dir, SNAP_RETAIN_COUNT)) {
+                Ok(()) => println!("{} took a snapshot at zxid {}", id, snapshot.zxid),
+                Err(e) => println!("{} failed to take a snapshot at zxid {}: {}", id, snapshot.zxid, e),
+            }
+        });
+    }
+
+    fn install_snapshot(n: &mut Node, snapshot: &Snapshot) {
+        n.data = snapshot.data.clone();
+        n.last_applied = snapshot.zxid;
+        n.last_snapshot = snapshot.zxid;
+        // a snapshot only ever holds committed txs
+        n.last_committed_zxid = n.last_committed_zxid.max(snapshot.zxid);
+        n.sessions = SessionTracker::new();
+        for (session_id, timeout) in snapshot.sessions.iter() {
+            n.sessions.add(*session_id, *timeout);
+        }
+    }
+
+    fn prepare_tx(n: &mut Node, tx: &mut Transaction) -> Result<(), ZkError> {
+        // check against committed data plus whatever is still being proposed,
+        // so two conditional writes racing on the same version can't both pass
+        n.pending_changes.name_sequential(&n.data, tx)?;
+        n.pending_changes.check(&n.data, tx)
+    }
+
+    // leader only, stamps the tx and broadcasts it as a proposal if it would succeed, returning the zxid it's proposed under
+    async fn propose(n: &mut Node, tx: Transaction, origin: Option<(i32, i64)>) -> Result<i64, ZkError> {
+        let mut new_tx = tx;
+        let last_zxid = Node::last_zxid(n);
+        new_tx.zxid = if zxid_epoch(last_zxid) == n.current_epoch {
+            if zxid_counter(last_zxid) == 0xffff_ffff {
+                // like ZooKeeper it forces a new election, the next leader's epoch restarts the counter
+                println!("{} ran out of zxids in epoch {}, it steps down", n.id, n.current_epoch);
+                Node::starts_running(n).await;
+                return Err(ZkError::ConnectionLoss);
+            }
+            last_zxid + 1
         } else {
-            report = format!("id: {}, status: {:?}, leader: {} epoch {} ", n.id, n.status, n.leader_id.unwrap(), n.epoch);
+            make_zxid(n.current_epoch, 1)
+        };
+        new_tx.time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
+        if let TxActions::CreateSession = new_tx.action {
+            new_tx.timeout = SessionTracker::negotiate_timeout(new_tx.timeout);
+        }
+        if let Err(e) = Node::prepare_tx(n, &mut new_tx) {
+            println!("{} rejected {:?} on {}: {:?}", n.id, new_tx.action, new_tx.key, e);
+            return Err(e);
+        }
+        // the leader's own ack, it has to be durable before anyone else is asked for theirs
+        if let Err(e) = n.log.append(&new_tx) {
+            println!("{} failed to log zxid {}: {}", n.id, new_tx.zxid, e);
+            return Err(ZkError::ConnectionLoss);
+        }
+        // only recorded once it's logged, a write that's never proposed mustn't stay in the way of later ones.
+        // It passed the check against the same overlay above
+        let _ = n.pending_changes.apply(&n.data, &new_tx, new_tx.zxid);
+        n.history.push(new_tx.clone());
+        n.outstanding.insert(new_tx.zxid, HashSet::from([n.id]));
+
+        let new_write_zxid = new_tx.zxid;
+        let mut new_write = Message::new(n.id, MessageType::Write);
+        new_write.tx = new_tx;
+        new_write.epoch = n.current_epoch;
+        new_write.origin = origin;
+        Node::broadcast(n, new_write).await;
+        Ok(new_write_zxid)
+    }
+
+    // A broadcasting leader proposes a client's write itself, a learner that's up to date passes it on to its leader
+    // like ZooKeeper's FollowerRequestProcessor, and the client hears back from the server it sent the write to once
+    // that server applied it. A write that comes in while there's no leader to take it waits for the next one
+    async fn client_write(n: &mut Node, tx: Transaction, reply: Reply) {
+        if Node::is_broadcasting(n) {
+            if tx.session_id != 0 {
+                n.sessions.touch(tx.session_id);
+            }
+            match Node::propose(n, tx, None).await {
+                Ok(zxid) => { n.pending_writes.insert(zxid, reply); }
+                Err(e) => { let _ = reply.send(Err(e)); }
+            }
+            return;
+        }
+        let (Some(leader_id), ZabPhase::Broadcast) = (n.leader_id, n.phase) else {
+            if n.queued_writes.len() >= MAX_QUEUED_WRITES {
+                let _ = reply.send(Err(ZkError::ConnectionLoss));
+                return;
+            }
+            println!("{} queues a write until it's up to date with a leader", n.id);
+            n.queued_writes.push((tx, reply));
+            return;
+        };
+        let request_id = n.next_request_id;
+        n.next_request_id += 1;
+        n.forwarded.insert(request_id, reply);
+        let mut request = Message::new(n.id, MessageType::Request);
+        request.receiver_id = leader_id;
+        request.epoch = n.current_epoch;
+        request.origin = Some((n.id, request_id));
+        request.tx = tx;
+        Node::broadcast(n, request).await;
+    }
+
+    // Like ZooKeeper's sync, the client hears back once the server it's connected to committed everything the leader
+    // had committed when it got the sync, so its next reads there see every write that finished before it.
+    // A learner asks its leader how far it committed, a server that isn't serving can't catch up with anyone
+    async fn client_sync(n: &mut Node, reply: Reply) {
+        if Node::is_broadcasting(n) {
+            let _ = reply.send(Ok(n.last_committed_zxid));
+            return;
+        }
+        let (Some(leader_id), true) = (n.leader_id, Node::is_serving(n)) else {
+            let _ = reply.send(Err(ZkError::ConnectionLoss));
+            return;
+        };
+        let request_id = n.next_request_id;
+        n.next_request_id += 1;
+        n.forwarded.insert(request_id, reply);
+        let mut sync = Message::new(n.id, MessageType::Sync);
+        sync.receiver_id = leader_id;
+        sync.epoch = n.current_epoch;
+        sync.origin = Some((n.id, request_id));
+        Node::broadcast(n, sync).await;
+    }
+
+    // once it's broadcasting or up to date, a write whose client stopped waiting isn't made after all,
+    // that client took it for lost and may have sent it again
+    async fn flush_queued_writes(n: &mut Node) {
+        for (tx, reply) in std::mem::take(&mut n.queued_writes) {
+            if !reply.is_closed() {
+                Node::client_write(n, tx, reply).await;
+            }
+        }
+    }
+
+    // the proposals at the front of outstanding that reached a quorum, in zxid order. One that's short of acks holds back
+    // the ones after it even if they have theirs, a commit covers every zxid before it
+    pub fn take_committable(n: &mut Node) -> Vec<i64> {
+        let mut committable = Vec::new();
+        while let Some((zxid, acks)) = n.outstanding.first_key_value() {
+            if !Node::has_quorum(n, acks.len()) {
+                break;
+            }
+            let zxid = *zxid;
+            n.outstanding.remove(&zxid);
+            committable.push(zxid);
         }
+        committable
+    }
+
+    // history was replaced wholesale, make the log match it. Until it does the node can't ack what it holds
+    fn rewrite_log(n: &mut Node) -> io::Result<()> {
+        let first_zxid = n.last_snapshot + 1;
+        n.log.rewrite(first_zxid, &n.history)
+    }
+
+    async fn rebuild_data(n: &mut Node, snapshot: &Snapshot) {
+        // history was replaced wholesale, replay what of it is committed on top of the snapshot it follows,
+        // the rest waits for a commit or the leader's UPTODATE
+        Node::install_snapshot(n, snapshot);
+        // replaying isn't a change clients should hear about, keep the watches out of it
+        let watches = std::mem::replace(&mut n.watches, WatchManager::new());
+        let zxid = n.last_committed_zxid;
+        Node::execute_tx(n, zxid).await;
+        n.watches = watches;
+    }
+
+    pub fn node_report(n: &Node) {
+        let mut report = match n.leader_id {
+            None => format!("id: {}, status: {:?}, phase: {:?}, leader: None accepted epoch {} current epoch {} committed {:#x} ", n.id, n.status, n.phase, n.accepted_epoch, n.current_epoch, n.last_committed_zxid),
+            Some(leader_id) => format!("id: {}, status: {:?}, phase: {:?}, leader: {} accepted epoch {} current epoch {} committed {:#x} ", n.id, n.status, n.phase, leader_id, n.accepted_epoch, n.current_epoch, n.last_committed_zxid),
+        };
 
 
         for i in 0..n.history.len() {
             let tx = &n.history[i];
-            let tx_report = &format!(" |zxid: {}, action: {:?}, key: {}, val: {} |", tx.zxid, tx.action, tx.key, tx.val);
-            report = report + tx_report;
+            let mut tx_report = format!(" |zxid: {:#x}, action: {:?}, key: {}, val: {} |", tx.zxid, tx.action, tx.key, tx.val);
+            for op in tx.ops.iter() {
+                tx_report += &format!(" ({:?}, {}, {})", op.action, op.key, op.val);
+            }
+            report = report + &tx_report;
         }
-        println!("{}", report.to_string());
+        report += &format!(" data: {:?}", n.data);
+        println!("{}", report);
         io::stdout().flush().unwrap(); // Ensure prompt is shown immediately
     }
 
@@ -185,151 +883,445 @@ impl Node {
             sleep(Duration::from_millis(10)).await;
 
             let mut n = node.lock().await; // Acquire the lock here
-            while !n.receiver.is_empty() {
-                let msg = n.receiver.recv().await.unwrap();
+            // a stopped node leaves whatever is still queued unanswered
+            while !n.receiver.is_empty() && n.status != NodeStatus::Quiting {
+                let msg = match n.receiver.recv().await {
+                    Ok(msg) => msg,
+                    // it fell behind while something held its lock, what it missed is gone like on a dropped
+                    // connection. It goes on with what comes next, the next election resyncs whoever fell out of step
+                    Err(RecvError::Lagged(missed)) => {
+                        println!("{} lagged behind and missed {} messages", n.id, missed);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
                 if msg.sender_id == n.id { continue; }
                 // -1 stands for broadcast to all, -2 stands for zookeeper
                 if msg.receiver_id != -1 && msg.receiver_id != n.id { continue; }
 
 
-                // println!("id {} , status {:?}, leader {:?}, epoch {}, ",n.id, n.status, n.leader_id, n.epoch);
+                // println!("id {} , status {:?}, leader {:?}, epoch {}, ",n.id, n.status, n.leader_id, n.current_epoch);
 
                 match msg.msg_type {
                     MessageType::Quit => {
-                        // Should save tx history first
-                        println!("{} is saving transaction histories", n.id);
-                        n.status = NodeStatus::Quiting;
+                        // every tx was logged before it was proposed or acked, nothing left to save
+                        println!("{} is quiting", n.id);
+                        let phase = n.phase;
+                        Node::set_state(&mut n, NodeStatus::Quiting, phase);
                         break;
                     }
 
                     MessageType::Commit => {
-                        if n.status == NodeStatus::Following || n.leader_id != None { continue; }
-
-                        if n.leader_id.unwrap() == msg.sender_id && msg.epoch == n.epoch && msg.last_zxid == Node::last_zxid(&n) {
-                            Node::execute_tx(&mut n).await;
+                        if Node::learns_from(&n, msg.sender_id) && msg.epoch == n.accepted_epoch && n.synced && msg.last_zxid <= Node::last_zxid(&n) {
+                            n.last_heartbeat = Instant::now(); // any message from the leader proves it's alive
+                            Node::execute_tx(&mut n, msg.last_zxid).await;
                         }
                     }
 
                     MessageType::AckTX => {
-                        if n.status != NodeStatus::Leading { continue; }
-                        if msg.last_zxid == Node::last_zxid(&n) && msg.epoch == n.epoch {
-                            n.supporting_nodes.insert(msg.sender_id);
-                        }
+                        if !Node::is_broadcasting(&n) || msg.epoch != n.current_epoch { continue; }
+                        // late acks of an already committed tx are ignored
+                        let Some(acks) = n.outstanding.get_mut(&msg.last_zxid) else { continue };
+                        acks.insert(msg.sender_id);
 
-                        if n.supporting_nodes.len() as i32 > (n.cluster_size + 1) / 2 {
+                        for zxid in Node::take_committable(&mut n) {
                             let mut commit = Message::new(n.id, MessageType::Commit);
-                            commit.epoch = n.epoch;
-                            commit.last_zxid = Node::last_zxid(&n);
+                            commit.epoch = n.current_epoch;
+                            commit.last_zxid = zxid;
                             Node::broadcast(&n, commit).await;
-                            Node::execute_tx(&mut n).await;
+                            Node::execute_tx(&mut n, zxid).await;
                         }
                     }
 
                     MessageType::Write => {
-                        if n.status == NodeStatus::Leading {
-                            //should only come from client
-                            let mut new_tx = msg.tx.clone();
-                            new_tx.zxid = Node::last_zxid(&n) + 1;
-                            n.history.push(new_tx.clone());
-
-                            let mut new_write = Message::new(n.id, MessageType::Write);
-                            new_write.tx = new_tx;
-                            new_write.epoch = n.epoch;
-                            Node::broadcast(&n, new_write).await;
+                        // a client's write, any server takes it
+                        if msg.sender_id == -2 {
+                            let Some(reply) = msg.reply else { continue };
+                            Node::client_write(&mut n, msg.tx, reply).await;
+                            continue;
                         }
-                        if n.status == NodeStatus::Following && n.leader_id != None && n.leader_id.unwrap() == msg.sender_id {
+                        // proposals made before its sync are part of the sync, the ones after it follow it
+                        if Node::learns_from(&n, msg.sender_id) && msg.epoch == n.accepted_epoch && n.synced {
+                            n.last_heartbeat = Instant::now();
+                            // no ack unless the proposal made it to disk
+                            if let Err(e) = n.log.append(&msg.tx) {
+                                println!("{} failed to log zxid {}: {}", n.id, msg.tx.zxid, e);
+                                continue;
+                            }
+                            let zxid = msg.tx.zxid;
                             n.history.push(msg.tx);
+                            // one of its own clients' writes, that client hears back once it's applied here
+                            if let Some((origin, request_id)) = msg.origin {
+                                if origin == n.id {
+                                    if let Some(reply) = n.forwarded.remove(&request_id) {
+                                        n.pending_writes.insert(zxid, reply);
+                                    }
+                                }
+                            }
+                            // an observer's ack wouldn't count anyway
+                            if n.observer { continue; }
 
                             let mut ack = Message::new(n.id, MessageType::AckTX);
                             ack.receiver_id = n.leader_id.unwrap();
-                            ack.last_zxid = Node::last_zxid(&n);
+                            // acks are per proposal, the leader may have many in flight
+                            ack.last_zxid = zxid;
                             ack.epoch = msg.epoch;
+                            Node::broadcast(&n, ack).await;
                         }
                     }
 
+                    MessageType::Request => {
+                        let Some((origin, request_id)) = msg.origin else { continue };
+                        let result = if Node::is_broadcasting(&n) && msg.epoch == n.current_epoch {
+                            if msg.tx.session_id != 0 {
+                                n.sessions.touch(msg.tx.session_id);
+                            }
+                            Node::propose(&mut n, msg.tx, msg.origin).await
+                        } else {
+                            // it was deposed since, the learner has to find the new leader
+                            Err(ZkError::ConnectionLoss)
+                        };
+                        // a proposed write is answered by the learner once it applies it
+                        let Err(e) = result else { continue };
+                        let mut answer = Message::new(n.id, MessageType::Reply);
+                        answer.receiver_id = origin;
+                        answer.origin = Some((origin, request_id));
+                        answer.error = Some(e);
+                        Node::broadcast(&n, answer).await;
+                    }
+
                     MessageType::Sync => {
-                        if n.status != NodeStatus::Following && n.leader_id != None && n.leader_id.unwrap() == msg.sender_id { continue; }
-                        n.history = msg.history.clone();
+                        // a client's sync, the server it's connected to catches up with the leader before answering
+                        if msg.sender_id == -2 {
+                            let Some(reply) = msg.reply else { continue };
+                            Node::client_sync(&mut n, reply).await;
+                            continue;
+                        }
+                        let Some((origin, request_id)) = msg.origin else { continue };
+                        let mut answer = Message::new(n.id, MessageType::Reply);
+                        answer.receiver_id = origin;
+                        answer.origin = Some((origin, request_id));
+                        if Node::is_broadcasting(&n) && msg.epoch == n.current_epoch {
+                            answer.last_zxid = n.last_committed_zxid;
+                        } else {
+                            answer.error = Some(ZkError::ConnectionLoss);
+                        }
+                        Node::broadcast(&n, answer).await;
                     }
 
-                    MessageType::SyncRequest => {
+                    MessageType::Reply => {
+                        if !Node::learns_from(&n, msg.sender_id) { continue; }
+                        let Some((_, request_id)) = msg.origin else { continue };
+                        let Some(reply) = n.forwarded.remove(&request_id) else { continue };
+                        match msg.error {
+                            Some(e) => { let _ = reply.send(Err(e)); }
+                            None => {
+                                n.pending_syncs.push((msg.last_zxid, reply));
+                                Node::resolve_syncs(&mut n);
+                            }
+                        }
+                    }
+
+                    MessageType::AckHeartbeat => {
+                        if !Node::is_broadcasting(&n) || msg.epoch != n.current_epoch || n.observers.contains(&msg.sender_id) { continue; }
+                        let Some((_, acks)) = n.heartbeat_acks.get_mut(&msg.round) else { continue };
+                        acks.insert(msg.sender_id);
+                        let (sent, acked) = (n.heartbeat_acks[&msg.round].0, n.heartbeat_acks[&msg.round].1.len());
+                        if Node::has_quorum(&n, acked) {
+                            // a quorum heard from it after this one was sent, the earlier rounds can't extend the lease anymore
+                            n.lease_until = n.lease_until.max(Some(sent + LEASE));
+                            n.heartbeat_acks = n.heartbeat_acks.split_off(&(msg.round + 1));
+                        }
+                    }
+
+                    MessageType::Ping => {
                         if n.status != NodeStatus::Leading { continue; }
-                        let mut sync_msg = Message::new(msg.sender_id, MessageType::Sync);
-                        sync_msg.history = n.history.clone();
-                        Node::broadcast(&n, sync_msg).await;
+                        n.sessions.touch(msg.tx.session_id);
+                    }
+
+                    MessageType::Diff => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization || msg.epoch != n.accepted_epoch { continue; }
+                        if msg.last_zxid != Node::last_zxid(&n) {
+                            // its log moved since it told the leader where it ends, it has to go through discovery again
+                            println!("{} can't apply a diff from zxid {:#x}, its log ends at {:#x}", n.id, msg.last_zxid, Node::last_zxid(&n));
+                            Node::give_up_election(&mut n).await;
+                            continue;
+                        }
+                        let mut logged = true;
+                        for tx in msg.history {
+                            if let Err(e) = n.log.append(&tx) {
+                                println!("{} failed to log zxid {}: {}", n.id, tx.zxid, e);
+                                logged = false;
+                                break;
+                            }
+                            n.history.push(tx);
+                        }
+                        // nothing in it counts as committed until the leader's UPTODATE or commits say so
+                        if !logged { continue; }
+                        n.last_heartbeat = Instant::now();
+                        n.synced = true;
                     }
 
-                    MessageType::Recovery => {
+                    MessageType::Trunc => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization || msg.epoch != n.accepted_epoch { continue; }
+                        println!("{} truncates its log to zxid {:#x}", n.id, msg.last_zxid);
+                        if let Err(e) = Node::truncate(&mut n, msg.last_zxid).await {
+                            // ZooKeeper gives up on the process here, this node only gives up on the leader
+                            println!("{} failed to truncate its log to zxid {:#x}: {}", n.id, msg.last_zxid, e);
+                            Node::give_up_election(&mut n).await;
+                        }
+                    }
+
+                    MessageType::Snap => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization || msg.epoch != n.accepted_epoch { continue; }
+                        let Some(snapshot) = msg.snapshot else { continue };
+                        if let Err(e) = Node::install_history(&mut n, &snapshot, msg.history).await {
+                            println!("{} failed to install the synced snapshot: {}", n.id, e);
+                            continue;
+                        }
+                        n.last_heartbeat = Instant::now();
+                        n.synced = true;
+                    }
+
+                    MessageType::NewLeader => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization || msg.epoch != n.accepted_epoch || !n.synced { continue; }
+                        // it holds the leader's history now, so it's current in the leader's epoch
+                        if let Err(e) = Node::set_current_epoch(&mut n, msg.epoch) {
+                            println!("{} failed to move to epoch {}: {}", n.id, msg.epoch, e);
+                            continue;
+                        }
+                        n.last_heartbeat = Instant::now();
+                        let mut ack = Message::new(n.id, MessageType::AckNewLeader);
+                        ack.receiver_id = msg.sender_id;
+                        ack.epoch = msg.epoch;
+                        Node::broadcast(&n, ack).await;
+                    }
+
+                    MessageType::AckNewLeader => {
+                        if !Node::is_established(&n) || msg.epoch != n.current_epoch { continue; }
+                        if n.phase == ZabPhase::Broadcast {
+                            // a follower that came late, the others were told already
+                            let mut up_to_date = Message::new(n.id, MessageType::UpToDate);
+                            up_to_date.receiver_id = msg.sender_id;
+                            up_to_date.epoch = n.current_epoch;
+                            up_to_date.last_zxid = n.last_committed_zxid;
+                            Node::broadcast(&n, up_to_date).await;
+                            continue;
+                        }
+                        n.newleader_acks.insert(msg.sender_id);
+                        let voters = n.newleader_acks.iter().filter(|id| !n.observers.contains(id)).count();
+                        if !Node::has_quorum(&n, voters) { continue; }
+                        if !Node::set_state(&mut n, NodeStatus::Leading, ZabPhase::Broadcast) { continue; }
+
+                        println!("{} is broadcasting in epoch {}", n.id, n.current_epoch);
+                        // a quorum holds its whole history now, so all of it is committed
+                        let zxid = Node::last_zxid(&n);
+                        Node::execute_tx(&mut n, zxid).await;
+                        Node::flush_queued_writes(&mut n).await;
+                        // a new leader can't know when clients last pinged the old one
+                        n.sessions.touch_all();
+                        let followers: Vec<i32> = n.newleader_acks.iter().filter(|id| **id != n.id).cloned().collect();
+                        for follower_id in followers {
+                            let mut up_to_date = Message::new(n.id, MessageType::UpToDate);
+                            up_to_date.receiver_id = follower_id;
+                            up_to_date.epoch = n.current_epoch;
+                            up_to_date.last_zxid = n.last_committed_zxid;
+                            Node::broadcast(&n, up_to_date).await;
+                        }
+                    }
+
+                    MessageType::UpToDate => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization || msg.epoch != n.current_epoch || !n.synced { continue; }
+                        let status = n.status.clone();
+                        if !Node::set_state(&mut n, status, ZabPhase::Broadcast) { continue; }
+                        n.last_heartbeat = Instant::now();
+                        // what it synced is committed as far as its leader committed, the commits that follow take care of the rest
+                        let zxid = msg.last_zxid.min(Node::last_zxid(&n));
+                        Node::execute_tx(&mut n, zxid).await;
+                        Node::flush_queued_writes(&mut n).await;
+                    }
+
+                    MessageType::FollowerInfo => {
                         if n.status != NodeStatus::Leading { continue; }
-                        let follower_history = msg.tx;
-                        // history is sorted from low idx to hi -> lo to hi zxid
-                        // go through history low to high, see if the gaps between two tx can fit in this one
-                        if n.history.is_empty() {
-                            n.history.push(follower_history.clone());
-                        } else {
-                            for i in 0..n.history.len() - 1 {
-                                let left = n.history[i].zxid;
-                                let right = n.history[i + 1].zxid;
-                                if left < follower_history.zxid && follower_history.zxid < right {
-                                    n.history.insert(i + 1, follower_history.clone());
-                                }
+                        if msg.state == NodeStatus::Observing {
+                            // observers have no say in the new epoch, they're let in once it's established
+                            if !Node::is_established(&n) { continue; }
+                            n.observers.insert(msg.sender_id);
+                        }
+                        if n.accepted_epoch == n.new_epoch {
+                            // the epoch is settled already, a follower that comes late is only told which one it is
+                            let mut new_epoch = Message::new(n.id, MessageType::NewEpoch);
+                            new_epoch.receiver_id = msg.sender_id;
+                            new_epoch.epoch = n.new_epoch;
+                            Node::broadcast(&n, new_epoch).await;
+                            continue;
+                        }
+                        // the new epoch has to be above what any of a quorum promised, so none of them follows an older leader again
+                        n.connecting_followers.insert(msg.sender_id);
+                        n.new_epoch = n.new_epoch.max(msg.epoch + 1);
+                        if !Node::has_quorum(&n, n.connecting_followers.len()) { continue; }
+
+                        let epoch = n.new_epoch;
+                        if let Err(e) = Node::set_accepted_epoch(&mut n, epoch) {
+                            println!("{} failed to accept epoch {}: {}", n.id, epoch, e);
+                            continue;
+                        }
+                        let followers: Vec<i32> = n.connecting_followers.iter().filter(|id| **id != n.id).cloned().collect();
+                        for follower_id in followers {
+                            let mut new_epoch = Message::new(n.id, MessageType::NewEpoch);
+                            new_epoch.receiver_id = follower_id;
+                            new_epoch.epoch = epoch;
+                            Node::broadcast(&n, new_epoch).await;
+                        }
+                    }
+
+                    MessageType::NewEpoch => {
+                        // its FOLLOWERINFO may have gone out more than once, only the first answer counts
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Discovery { continue; }
+                        if msg.epoch < n.accepted_epoch {
+                            // it promised a newer leader already, this one is stale
+                            println!("{} won't follow {} into epoch {}, it accepted epoch {}", n.id, msg.sender_id, msg.epoch, n.accepted_epoch);
+                            Node::give_up_election(&mut n).await;
+                            continue;
+                        }
+                        if let Err(e) = Node::set_accepted_epoch(&mut n, msg.epoch) {
+                            println!("{} failed to accept epoch {}: {}", n.id, msg.epoch, e);
+                            continue;
+                        }
+                        let status = n.status.clone();
+                        if !Node::set_state(&mut n, status, ZabPhase::Synchronization) { continue; }
+                        n.last_heartbeat = Instant::now();
+
+                        let mut ack = Message::new(n.id, MessageType::AckEpoch);
+                        ack.receiver_id = msg.sender_id;
+                        ack.epoch = msg.epoch;
+                        ack.current_epoch = n.current_epoch;
+                        ack.last_zxid = Node::last_zxid(&n);
+                        Node::broadcast(&n, ack).await;
+                    }
+
+                    MessageType::GetHistory => {
+                        if !Node::learns_from(&n, msg.sender_id) || n.phase != ZabPhase::Synchronization { continue; }
+                        let mut history = Message::new(n.id, MessageType::History);
+                        history.receiver_id = msg.sender_id;
+                        history.epoch = msg.epoch;
+                        history.last_zxid = Node::last_zxid(&n);
+                        Node::attach_state(&n, &mut history);
+                        Node::broadcast(&n, history).await;
+                    }
+
+                    MessageType::History => {
+                        if n.status != NodeStatus::Leading || n.phase != ZabPhase::Discovery || msg.epoch != n.accepted_epoch { continue; }
+                        // only the answer of the follower it asked last, about the history that follower acked
+                        let Some((id, _, last_zxid)) = n.freshest else { continue };
+                        if id != msg.sender_id || last_zxid != msg.last_zxid { continue; }
+                        println!("{} takes over the history of {} up to zxid {:#x}", n.id, id, last_zxid);
+                        let Some(snapshot) = msg.snapshot else { continue };
+                        if let Err(e) = Node::install_history(&mut n, &snapshot, msg.history).await {
+                            println!("{} failed to install the history of {}: {}", n.id, id, e);
+                            continue;
+                        }
+                        n.freshest = None;
+                        Node::establish_epoch(&mut n).await;
+                    }
+
+                    MessageType::AckEpoch => {
+                        if n.status != NodeStatus::Leading || msg.epoch != n.accepted_epoch { continue; }
+                        if Node::is_established(&n) {
+                            Node::sync_follower(&n, msg.sender_id, msg.last_zxid).await;
+                            continue;
+                        }
+                        let had_quorum = Node::has_quorum(&n, n.electing_followers.len());
+                        n.electing_followers.insert(msg.sender_id, msg.last_zxid);
+                        // the history a quorum agrees on ranks like votes do, by current epoch then last zxid
+                        let freshest = n.freshest.map_or((n.current_epoch, Node::last_zxid(&n)), |(_, epoch, zxid)| (epoch, zxid));
+                        let fresher = (msg.current_epoch, msg.last_zxid) > freshest;
+                        if fresher {
+                            n.freshest = Some((msg.sender_id, msg.current_epoch, msg.last_zxid));
+                        }
+                        if !Node::has_quorum(&n, n.electing_followers.len()) { continue; }
+
+                        // a leader behind its quorum takes over the freshest history first, only that follower ships it
+                        if let Some((id, _, _)) = n.freshest {
+                            if fresher || !had_quorum {
+                                let mut ask = Message::new(n.id, MessageType::GetHistory);
+                                ask.receiver_id = id;
+                                ask.epoch = n.accepted_epoch;
+                                Node::broadcast(&n, ask).await;
                             }
+                            continue;
                         }
+                        Node::establish_epoch(&mut n).await;
                     }
 
                     MessageType::Heartbeat => {
                         // receiver can decide if they wanna follow msg sender as leader
                         // println!("msg from {} to {}, {:?}", msg.sender_id, msg.receiver_id, msg.msg_type);
+                        let rank = (msg.epoch, msg.last_zxid, msg.sender_id);
 
                         match n.status {
-                            NodeStatus::Following => {
-                                if n.leader_id == None {
-                                    if Node::sender_is_better_leader(&n, &msg) {
-                                        Node::follow_and_discovery(&mut n, &msg).await;
-                                    } else {
-                                        n.epoch = msg.epoch;
-                                    }
+                            NodeStatus::Following | NodeStatus::Observing => {
+                                // there's no point waiting out the timeout for a leader its own leader gives up for
+                                let leader_yields = n.leader_id != Some(msg.sender_id) && msg.epoch >= n.accepted_epoch && rank > n.leader_rank;
+                                if leader_yields && Node::looking(&mut n) {
+                                    Node::follow_and_discovery(&mut n, msg.sender_id, rank).await;
+                                    continue;
                                 }
 
-                                if n.leader_id != None && n.leader_id.unwrap() == msg.sender_id {
+                                // the leader may not have been leading yet when it first heard from this node
+                                if n.leader_id == Some(msg.sender_id) && n.phase == ZabPhase::Discovery {
+                                    Node::send_follower_info(&n).await;
+                                }
+                                // a leader that started discovery over never answers, so until it's synced only discovery messages count
+                                if n.leader_id == Some(msg.sender_id) && n.synced && msg.epoch == n.accepted_epoch {
                                     n.last_heartbeat = Instant::now();
-                                    n.epoch = msg.epoch;
+                                    n.leader_rank = rank;
+                                    // it won't give up on this leader for another 5s, which is what the leader's lease rests on
+                                    if !n.observer {
+                                        let mut ack = Message::new(n.id, MessageType::AckHeartbeat);
+                                        ack.receiver_id = msg.sender_id;
+                                        ack.epoch = n.current_epoch;
+                                        ack.round = msg.round;
+                                        Node::broadcast(&n, ack).await;
+                                    }
                                 }
                             }
 
-                            NodeStatus::Running => {
-                                if Node::sender_is_better_leader(&n, &msg) {
-                                    Node::give_up_election(&mut n).await;
+                            NodeStatus::Looking => {
+                                // an observer has no vote, any leader that isn't stale will do.
+                                // A node looking through the fast leader election only goes by notifications
+                                let follows = if n.observer {
+                                    msg.epoch >= n.accepted_epoch
+                                } else {
+                                    n.election_alg == ElectionAlg::Campaign && Node::sender_is_better_leader(&n, &msg)
+                                };
+                                if follows {
+                                    Node::follow_and_discovery(&mut n, msg.sender_id, rank).await;
                                 }
                             }
 
                             NodeStatus::Leading => {
-                                if Node::sender_is_better_leader(&n, &msg) {
-                                    Node::give_up_election(&mut n).await;
-                                    Node::follow_and_discovery(&mut n, &msg).await;
-                                } else {
-                                    Node::starts_running(&mut n).await;
+                                // a worse leader gives up once it hears from this one, running again would only throw discovery away
+                                if Node::sender_is_better_leader(&n, &msg) && Node::looking(&mut n) {
+                                    Node::follow_and_discovery(&mut n, msg.sender_id, rank).await;
                                 }
                             }
 
                             NodeStatus::Quiting => {
                                 break
                             }
-
-                            // _ =>{
-                            //     println!("Default case")
-                            // }
-
                         }
                     }
 
                     MessageType::Running => {
-                        // another node is running
+                        // another node is running, an observer has no say in it
+                        if n.observer { continue; }
                         let mut answer = Message::new(n.id, MessageType::Blank);
-                        answer.epoch = msg.epoch; // todo: bounce back the proposed epoch?
                         answer.last_zxid = Node::last_zxid(&n);
                         answer.receiver_id = msg.sender_id;
 
+                        answer.epoch = n.current_epoch;
+
                         if Node::sender_is_better_leader(&n, &msg) {
                             answer.msg_type = MessageType::Approve;
                         } else {
@@ -340,27 +1332,54 @@ impl Node {
                     }
 
                     MessageType::Approve => {
-                        if n.status != NodeStatus::Running { continue; }
+                        if !Node::is_campaigning(&n) { continue; }
                         n.supporting_nodes.insert(msg.sender_id);
 
-                        if n.supporting_nodes.len() as i32 > (n.cluster_size + 1) / 2 {
+                        if Node::has_quorum(&n, n.supporting_nodes.len()) {
                             //Starts leading and send out heartbeats
                             //Later heartbeat will be interpreted as winning msg
-                            n.status = NodeStatus::Leading;
-                            n.leader_id = None;
-                            n.epoch += 1;
-                            n.supporting_nodes = HashSet::new();
+                            Node::start_leading(&mut n);
+                        }
+                    }
+
+                    MessageType::Notification => {
+                        if n.election_alg != ElectionAlg::Fast || n.observer { continue; }
+                        let vote = (msg.epoch, msg.last_zxid, msg.voted_for);
+                        match (&n.status, &msg.state) {
+                            (NodeStatus::Looking, NodeStatus::Looking) => {
+                                if msg.round < n.election.round {
+                                    let mut answer = Node::notification(&n);
+                                    answer.receiver_id = msg.sender_id;
+                                    Node::broadcast(&n, answer).await;
+                                    continue;
+                                }
+                                let (id, own) = (n.id, (n.current_epoch, Node::last_zxid(&n), n.id));
+                                if n.election.receive(id, own, msg.sender_id, vote, msg.round) {
+                                    n.last_heartbeat = Instant::now();
+                                    let notification = Node::notification(&n);
+                                    Node::broadcast(&n, notification).await;
+                                }
+                            }
+                            (NodeStatus::Looking, _) => {
+                                if let Some(vote) = n.election.receive_settled(msg.sender_id, vote, msg.round, msg.state == NodeStatus::Leading) {
+                                    Node::elected(&mut n, vote).await;
+                                }
+                            }
+                            (NodeStatus::Following | NodeStatus::Leading, NodeStatus::Looking) => {
+                                // tells the looking node who it settled on
+                                let mut answer = Node::notification(&n);
+                                answer.receiver_id = msg.sender_id;
+                                Node::broadcast(&n, answer).await;
+                            }
+                            _ => {}
                         }
                     }
 
                     MessageType::Reject => {
-                        if n.status != NodeStatus::Running { continue; }
+                        if !Node::is_campaigning(&n) { continue; }
 
                         if Node::sender_is_better_leader(&n, &msg) {
                             Node::give_up_election(&mut n).await;
-                        } else {
-                            //adjust epoch, ready for next campaign
-                            n.epoch = msg.epoch;
                         }
                     }
 
@@ -369,6 +1388,11 @@ impl Node {
                     }
                 }
             }
+            if n.status == NodeStatus::Looking && n.election_alg == ElectionAlg::Fast && !n.observer {
+                if let Some(vote) = n.election.decided(Instant::now()) {
+                    Node::elected(&mut n, vote).await;
+                }
+            }
             if n.status == NodeStatus::Quiting {
                 break;
             }
@@ -377,32 +1401,21 @@ impl Node {
 
     fn sender_is_better_leader(n: &Node, msg: &Message) -> bool {
         //compared to current node, if the msg sender is a better leader
-        // Does if sender is better depends on the state of this node as well?
-        // eg. if  this node is at epoch 2, heartbeat coming from another have a epoch 3, same zxid, should find it agreeable, right?
-
-        let zxid = Node::last_zxid(n);
-        let mut epoch = n.epoch;
-
-        if msg.msg_type == MessageType::Running {
-            epoch += 1
-        }
-
-        if msg.epoch < epoch || msg.last_zxid < zxid {
+        // a leader of an epoch older than the one this node promised is stale, it's fenced off
+        if msg.msg_type == MessageType::Heartbeat && msg.epoch < n.accepted_epoch {
             return false;
         }
 
-        if msg.last_zxid == zxid && msg.epoch == epoch { // tie
-            return if msg.sender_id > n.id { true } else { false };
-        }
-
-        true
+        // the same order ZooKeeper ranks votes by: current epoch, then last zxid, then id to break ties
+        (msg.epoch, msg.last_zxid, msg.sender_id) > (n.current_epoch, Node::last_zxid(n), n.id)
     }
 
 
     async fn heartbeat_emitter(node: Arc<Mutex<Node>>) {
         loop {
-            sleep(Duration::from_millis(20)).await;
-            let n = node.lock().await;
+            // well within the 5s a follower waits before it gives up on its leader
+            sleep(Duration::from_millis(500)).await;
+            let mut n = node.lock().await;
             if n.status == NodeStatus::Quiting {
                 break;
             }
@@ -410,24 +1423,46 @@ impl Node {
             if n.status == NodeStatus::Leading {
                 let mut heartbeat = Message::new(n.id, MessageType::Heartbeat);
                 heartbeat.last_zxid = Node::last_zxid(&n);
-                heartbeat.epoch = n.epoch;
+                heartbeat.epoch = n.accepted_epoch;
+                n.heartbeat_round += 1;
+                heartbeat.round = n.heartbeat_round;
+                // it acks its own, heartbeats older than a lease couldn't extend it anymore
+                let (round, id) = (n.heartbeat_round, n.id);
+                n.heartbeat_acks.retain(|_, (sent, _)| sent.elapsed() < LEASE);
+                n.heartbeat_acks.insert(round, (Instant::now(), HashSet::from([id])));
                 Node::broadcast(&n, heartbeat).await;
             }
         }
     }
 
     async fn starts_running(n: &mut Node) {
-        n.status = NodeStatus::Running;
-        n.leader_id = None;
+        if !Node::looking(n) {
+            return;
+        }
+
+        if n.election_alg == ElectionAlg::Fast {
+            let own = (n.current_epoch, Node::last_zxid(n), n.id);
+            n.election.start(n.id, own);
+            Node::node_report(n);
+            let notification = Node::notification(n);
+            Node::broadcast(n, notification).await;
+            return;
+        }
 
         n.supporting_nodes.insert(n.id);
 
         let mut begin_campaign = Message::new(n.id, MessageType::Running);
-        begin_campaign.last_zxid = Node::last_zxid(&n);
-        begin_campaign.epoch = n.epoch + 1;
+        begin_campaign.last_zxid = Node::last_zxid(n);
+        begin_campaign.epoch = n.current_epoch;
         Node::node_report(n);
-        Node::broadcast(&n, begin_campaign).await;
+        Node::broadcast(n, begin_campaign).await;
     }
+
+    // a looking node that asked for approvals and hasn't given up on them yet
+    fn is_campaigning(n: &Node) -> bool {
+        n.status == NodeStatus::Looking && n.election_alg == ElectionAlg::Campaign && !n.supporting_nodes.is_empty()
+    }
+
     async fn heartbeat_monitor(node: Arc<Mutex<Node>>) {
         loop {
             sleep(Duration::from_secs(1)).await;
@@ -435,21 +1470,83 @@ impl Node {
             if n.status == NodeStatus::Quiting {
                 break;
             }
-            if n.status == NodeStatus::Following && n.last_heartbeat.elapsed() > Duration::from_secs(5) {
+            // like ZooKeeper's initLimit, discovery and sync get 10 ticks, on either side
+            let init_limit = Duration::from_millis(10 * TICK_MS as u64);
+            let timeout = if n.synced { Duration::from_secs(5) } else { init_limit };
+            let learning = n.status == NodeStatus::Following || n.status == NodeStatus::Observing;
+            if learning && n.last_heartbeat.elapsed() > timeout {
+                println!("{} lost its leader {:?} in {:?}", n.id, n.leader_id, n.phase);
+                if n.observer {
+                    Node::looking(&mut n);
+                } else {
+                    Node::starts_running(&mut n).await;
+                }
+            }
+            let idle = n.status == NodeStatus::Looking && !n.observer && n.last_heartbeat.elapsed() > Duration::from_secs(5);
+            // a campaign that went nowhere starts over
+            if idle && n.election_alg == ElectionAlg::Campaign {
+                Node::starts_running(&mut n).await;
+            }
+            // like ZooKeeper's notification timeout, the vote goes out again in case it was lost
+            if idle && n.election_alg == ElectionAlg::Fast {
+                n.last_heartbeat = Instant::now();
+                let notification = Node::notification(&n);
+                Node::broadcast(&n, notification).await;
+            }
+            if n.status == NodeStatus::Leading && !Node::is_broadcasting(&n) && n.last_heartbeat.elapsed() > init_limit {
+                println!("{} couldn't get a quorum through discovery and sync in epoch {}", n.id, n.new_epoch);
                 Node::starts_running(&mut n).await;
             }
         }
     }
 
 
+    async fn session_monitor(node: Arc<Mutex<Node>>) {
+        loop {
+            sleep(Duration::from_millis(TICK_MS as u64)).await;
+            let mut n = node.lock().await;
+            if n.status == NodeStatus::Quiting {
+                break;
+            }
+            if !Node::is_broadcasting(&n) {
+                continue;
+            }
+            // expiry goes through the normal proposal path so every replica closes the session
+            for session_id in n.sessions.take_expired() {
+                println!("{} expiring session {}", n.id, session_id);
+                let _ = Node::propose(&mut n, Transaction::session_op(TxActions::CloseSession, session_id), None).await;
+            }
+        }
+    }
+
     pub async fn run(node: Arc<Mutex<Node>>) {
+        {
+            // replay what was committed before a restart, the uncommitted tail stays a proposal
+            // until the leader's sync either keeps it or truncates it
+            // the snapshot was loaded by new, only the log after it is left
+            let mut n = node.lock().await;
+            if let Some(outbox) = n.outbox_receiver.take() {
+                task::spawn(Node::deliver(outbox, n.sender.clone()));
+            }
+            if !n.history.is_empty() {
+                println!("{} replaying {} logged transactions after zxid {} up to the committed {}", n.id, n.history.len(), n.last_snapshot, n.last_committed_zxid);
+                let zxid = n.last_committed_zxid;
+                Node::execute_tx(&mut n, zxid).await;
+            }
+            // every node starts out looking, under the fast leader election it votes right away
+            if n.election_alg == ElectionAlg::Fast && !n.observer {
+                Node::starts_running(&mut n).await;
+            }
+        }
         // let listen_handler = task::spawn(Node::listen(node.clone()));
         let processing_handler = task::spawn(Node::answering(node.clone()));
         let emitter_handler = task::spawn(Node::heartbeat_emitter(node.clone()));
         let monitor_handler = task::spawn(Node::heartbeat_monitor(node.clone()));
+        let session_handler = task::spawn(Node::session_monitor(node.clone()));
 
         processing_handler.await.unwrap();
         emitter_handler.await.unwrap();
         monitor_handler.await.unwrap();
+        session_handler.await.unwrap();
     }
 }
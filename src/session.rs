@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+
+// Same bounds ZooKeeper uses: a session timeout is between 2 and 20 ticks
+pub const TICK_MS: i32 = 1000;
+pub const MIN_SESSION_TIMEOUT: i32 = 2 * TICK_MS;
+pub const MAX_SESSION_TIMEOUT: i32 = 20 * TICK_MS;
+
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub timeout: i32, // negotiated, in ms
+    pub last_ping: Instant,
+    pub closing: bool, // leader already proposed its close
+}
+
+// Every node tracks the sessions it has committed, only the leader expires them
+pub struct SessionTracker {
+    sessions: HashMap<i64, Session>,
+}
+
+impl SessionTracker {
+    pub fn new() -> Self {
+        SessionTracker { sessions: HashMap::new() }
+    }
+
+    pub fn negotiate_timeout(requested: i32) -> i32 {
+        requested.clamp(MIN_SESSION_TIMEOUT, MAX_SESSION_TIMEOUT)
+    }
+
+    pub fn add(&mut self, session_id: i64, timeout: i32) {
+        self.sessions.insert(session_id, Session { timeout, last_ping: Instant::now(), closing: false });
+    }
+
+    pub fn remove(&mut self, session_id: i64) {
+        self.sessions.remove(&session_id);
+    }
+
+    pub fn get(&self, session_id: i64) -> Option<&Session> {
+        self.sessions.get(&session_id)
+    }
+
+    // (id, negotiated timeout) of every session, what a snapshot needs to bring them back
+    pub fn timeouts(&self) -> Vec<(i64, i32)> {
+        self.sessions.iter().map(|(id, session)| (*id, session.timeout)).collect()
+    }
+
+    // false if the session is unknown, or already expired and being closed
+    pub fn touch(&mut self, session_id: i64) -> bool {
+        match self.sessions.get_mut(&session_id) {
+            Some(session) if !session.closing => {
+                session.last_ping = Instant::now();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // a new leader can't know when clients last pinged the old one, so everyone gets a full timeout
+    pub fn touch_all(&mut self) {
+        for session in self.sessions.values_mut() {
+            session.last_ping = Instant::now();
+            session.closing = false;
+        }
+    }
+
+    // marks expired sessions as closing so they are only reported once
+    pub fn take_expired(&mut self) -> Vec<i64> {
+        let mut expired = Vec::new();
+        for (id, session) in self.sessions.iter_mut() {
+            if !session.closing && session.last_ping.elapsed() > Duration::from_millis(session.timeout as u64) {
+                session.closing = true;
+                expired.push(*id);
+            }
+        }
+        expired
+    }
+}
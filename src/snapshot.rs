@@ -0,0 +1,41 @@
+use crate::codec::{put_i32, put_i64, put_record, take_i32, take_i64, take_record};
+use crate::data_tree::DataTree;
+
+// A node's applied state as of zxid, so a restart only has to replay the log after it
+#[derive(Clone, Debug)]
+pub struct Snapshot {
+    pub zxid: i64, // last tx applied to data
+    pub data: DataTree,
+    pub sessions: Vec<(i64, i32)>, // open sessions and their negotiated timeouts
+}
+
+impl Snapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        put_i64(self.zxid, &mut payload);
+        put_i32(self.sessions.len() as i32, &mut payload);
+        for (session_id, timeout) in self.sessions.iter() {
+            put_i64(*session_id, &mut payload);
+            put_i32(*timeout, &mut payload);
+        }
+        self.data.encode(&mut payload);
+
+        let mut out = Vec::new();
+        put_record(&payload, &mut out);
+        out
+    }
+
+    // None if the snapshot was only partly written or is otherwise corrupt
+    pub fn decode(bytes: &[u8]) -> Option<Snapshot> {
+        let mut input = bytes;
+        let mut payload = take_record(&mut input)?;
+        let zxid = take_i64(&mut payload)?;
+        let mut sessions = Vec::new();
+        for _ in 0..take_i32(&mut payload)? {
+            sessions.push((take_i64(&mut payload)?, take_i32(&mut payload)?));
+        }
+        let data = DataTree::decode(&mut payload)?;
+        if !payload.is_empty() || !input.is_empty() { return None; }
+        Some(Snapshot { zxid, data, sessions })
+    }
+}
@@ -0,0 +1,327 @@
+use crate::codec::{put_i32, put_i64, put_record, put_str, take_i32, take_i64, take_record, take_str, take_u8};
+use crate::data_tree::CreateMode;
+use crate::node::{Transaction, TxActions};
+use crate::snapshot::Snapshot;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+// Same knobs as ZooKeeper's snapCount and autopurge.snapRetainCount
+pub const SNAP_COUNT: i32 = 1000; // txs applied between two snapshots
+pub const SNAP_RETAIN_COUNT: usize = 3;
+
+// Zab's two epochs, kept next to the log in files named like ZooKeeper's
+pub const ACCEPTED_EPOCH: &str = "acceptedEpoch"; // last epoch this node promised to follow
+pub const CURRENT_EPOCH: &str = "currentEpoch"; // last epoch whose leader this node synced with
+const LAST_COMMITTED: &str = "lastCommittedZxid"; // what's logged up to it is committed, see write_last_committed
+
+// A node's data dir holds log segments named log.<zxid of their first tx> and snapshots named
+// snapshot.<last applied zxid>, both in hex like ZooKeeper's.
+// Segments are append only, every record is [payload length][adler32 of payload][payload].
+// A crash mid append leaves a torn record at the tail, it fails the checksum and is cut off on load.
+pub struct TxnLog {
+    dir: PathBuf,
+    file: File, // newest segment
+}
+
+impl TxnLog {
+    // opens (or creates) the log in dir, returning it with the newest valid snapshot
+    // and every intact tx logged after it
+    pub fn open(dir: &Path) -> io::Result<(TxnLog, Option<Snapshot>, Vec<Transaction>)> {
+        fs::create_dir_all(dir)?;
+        let mut snapshot = None;
+        for (zxid, path) in TxnLog::files(dir, "snapshot")?.into_iter().rev() {
+            match Snapshot::decode(&fs::read(&path)?) {
+                Some(s) => {
+                    snapshot = Some(s);
+                    break;
+                }
+                None => println!("{:?} is not a valid snapshot of zxid {}, trying an older one", path, zxid),
+            }
+        }
+        let from = snapshot.as_ref().map_or(0, |s| s.zxid);
+
+        let segments = TxnLog::files(dir, "log")?;
+        let mut txs = Vec::new();
+        for (_, path) in segments.iter() {
+            let bytes = fs::read(path)?;
+            let (segment_txs, valid_len) = TxnLog::decode_records(&bytes);
+            if valid_len < bytes.len() {
+                println!("{:?} has a torn tail, dropping {} bytes", path, bytes.len() - valid_len);
+                let file = OpenOptions::new().write(true).open(path)?;
+                file.set_len(valid_len as u64)?;
+                file.sync_all()?;
+            }
+            txs.extend(segment_txs.into_iter().filter(|tx| tx.zxid > from));
+        }
+
+        let file = match segments.last() {
+            Some((_, path)) => OpenOptions::new().append(true).open(path)?,
+            None => OpenOptions::new().append(true).create(true).open(TxnLog::segment_path(dir, from + 1))?,
+        };
+        Ok((TxnLog { dir: dir.to_path_buf(), file }, snapshot, txs))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    // the tx is on disk once this returns, only then may it be acked
+    pub fn append(&mut self, tx: &Transaction) -> io::Result<()> {
+        self.file.write_all(&TxnLog::encode_record(tx))?;
+        self.file.sync_data()
+    }
+
+    // starts a new segment at next_zxid, so the ones before it can be purged once a snapshot covers them
+    pub fn roll(&mut self, next_zxid: i64) -> io::Result<()> {
+        self.file = OpenOptions::new().append(true).create(true).open(TxnLog::segment_path(&self.dir, next_zxid))?;
+        Ok(())
+    }
+
+    // replaces every segment with one holding history, e.g. after a sync handed over a new one.
+    // It's written aside and renamed in before the old segments go, so a crash never leaves no log
+    pub fn rewrite(&mut self, first_zxid: i64, history: &[Transaction]) -> io::Result<()> {
+        let path = TxnLog::segment_path(&self.dir, first_zxid);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for tx in history.iter() {
+            file.write_all(&TxnLog::encode_record(tx))?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        for (_, segment) in TxnLog::files(&self.dir, "log")? {
+            if segment != path {
+                fs::remove_file(segment)?;
+            }
+        }
+        self.file = OpenOptions::new().append(true).open(&path)?;
+        Ok(())
+    }
+
+    // drops every tx logged after zxid and the snapshots taken after it, so a reload ends at zxid.
+    // Refuses if what's left couldn't be replayed, i.e. the segments right after the newest snapshot it keeps were purged
+    pub fn truncate(&mut self, zxid: i64) -> io::Result<()> {
+        let snapshots = TxnLog::files(&self.dir, "snapshot")?;
+        let base = snapshots.iter().rev().map(|(snap_zxid, _)| *snap_zxid).find(|snap_zxid| *snap_zxid <= zxid).unwrap_or(0);
+        let segments = TxnLog::files(&self.dir, "log")?;
+        if segments.first().is_some_and(|(first_zxid, _)| *first_zxid > base + 1) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("the log before zxid {:#x} was purged", zxid)));
+        }
+
+        for (snap_zxid, path) in snapshots.iter() {
+            if *snap_zxid > zxid {
+                fs::remove_file(path)?;
+            }
+        }
+        let mut newest = None;
+        for (first_zxid, path) in segments.iter() {
+            if *first_zxid > zxid {
+                fs::remove_file(path)?;
+                continue;
+            }
+            newest = Some(path.clone());
+            let (txs, _) = TxnLog::decode_records(&fs::read(path)?);
+            if txs.iter().all(|tx| tx.zxid <= zxid) { continue; }
+            let tmp = path.with_extension("tmp");
+            let mut file = File::create(&tmp)?;
+            for tx in txs.iter().filter(|tx| tx.zxid <= zxid) {
+                file.write_all(&TxnLog::encode_record(tx))?;
+            }
+            file.sync_all()?;
+            fs::rename(&tmp, path)?;
+        }
+        let path = newest.unwrap_or_else(|| TxnLog::segment_path(&self.dir, zxid + 1));
+        self.file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(())
+    }
+
+    // None if it was never written
+    pub fn read_epoch(&self, name: &str) -> io::Result<Option<i32>> {
+        match fs::read_to_string(self.dir.join(name)) {
+            Ok(epoch) => epoch.trim().parse().map(Some).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // the epoch is on disk once this returns, so a restart can't take the node back to an older one
+    // 0 if nothing was ever committed here
+    pub fn read_last_committed(&self) -> io::Result<i64> {
+        match fs::read_to_string(self.dir.join(LAST_COMMITTED)) {
+            Ok(zxid) => zxid.trim().parse().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    // written on every commit, but unlike the epochs not synced: a crash can only leave an older zxid behind,
+    // and the txs after it wait for the leader's sync like any other proposal
+    pub fn write_last_committed(&self, zxid: i64) -> io::Result<()> {
+        let path = self.dir.join(LAST_COMMITTED);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, zxid.to_string())?;
+        fs::rename(&tmp, &path)
+    }
+
+    pub fn write_epoch(&self, name: &str, epoch: i32) -> io::Result<()> {
+        let path = self.dir.join(name);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(epoch.to_string().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    // takes no &self so it can run off the node's lock, written aside and renamed in like the log
+    pub fn save_snapshot(dir: &Path, snapshot: &Snapshot) -> io::Result<()> {
+        let path = dir.join(format!("snapshot.{:x}", snapshot.zxid));
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&snapshot.encode())?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)
+    }
+
+    // keeps the newest `retain` snapshots and the segments needed to replay from the oldest of them
+    pub fn purge(dir: &Path, retain: usize) -> io::Result<()> {
+        let snapshots = TxnLog::files(dir, "snapshot")?;
+        if snapshots.len() <= retain { return Ok(()); }
+        let (old, kept) = snapshots.split_at(snapshots.len() - retain);
+        for (_, path) in old.iter() {
+            fs::remove_file(path)?;
+        }
+        let oldest_kept = kept[0].0;
+
+        // a segment only holds txs below where the next one starts, the newest one is never purged
+        let segments = TxnLog::files(dir, "log")?;
+        for pair in segments.windows(2) {
+            if pair[1].0 <= oldest_kept + 1 {
+                fs::remove_file(&pair[0].1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn segment_path(dir: &Path, first_zxid: i64) -> PathBuf {
+        dir.join(format!("log.{:x}", first_zxid))
+    }
+
+    // "<prefix>.<hex zxid>" files in dir, sorted by zxid, leftover .tmp files don't parse and are skipped
+    fn files(dir: &Path, prefix: &str) -> io::Result<Vec<(i64, PathBuf)>> {
+        let mut files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+            if let Some(zxid) = name.strip_prefix(prefix).and_then(|rest| rest.strip_prefix('.')) {
+                if let Ok(zxid) = i64::from_str_radix(zxid, 16) {
+                    files.push((zxid, path));
+                }
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    fn encode_record(tx: &Transaction) -> Vec<u8> {
+        let mut payload = Vec::new();
+        encode_tx(tx, &mut payload);
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        put_record(&payload, &mut record);
+        record
+    }
+
+    // returns the decoded txs and how many bytes of records were intact
+    fn decode_records(bytes: &[u8]) -> (Vec<Transaction>, usize) {
+        let mut txs = Vec::new();
+        let mut input = bytes;
+        let mut valid_len = 0;
+        while let Some(mut payload) = take_record(&mut input) {
+            match decode_tx(&mut payload) {
+                Some(tx) if payload.is_empty() => txs.push(tx),
+                _ => break,
+            }
+            valid_len = bytes.len() - input.len();
+        }
+        (txs, valid_len)
+    }
+}
+
+fn action_code(action: &TxActions) -> u8 {
+    match action {
+        TxActions::NA => 0,
+        TxActions::Del => 1,
+        TxActions::New => 2,
+        TxActions::Set => 3,
+        TxActions::Check => 4,
+        TxActions::Multi => 5,
+        TxActions::CreateSession => 6,
+        TxActions::CloseSession => 7,
+    }
+}
+
+fn action_from_code(code: u8) -> Option<TxActions> {
+    match code {
+        0 => Some(TxActions::NA),
+        1 => Some(TxActions::Del),
+        2 => Some(TxActions::New),
+        3 => Some(TxActions::Set),
+        4 => Some(TxActions::Check),
+        5 => Some(TxActions::Multi),
+        6 => Some(TxActions::CreateSession),
+        7 => Some(TxActions::CloseSession),
+        _ => None,
+    }
+}
+
+fn mode_code(mode: &CreateMode) -> u8 {
+    match mode {
+        CreateMode::Persistent => 0,
+        CreateMode::Ephemeral => 1,
+        CreateMode::Sequential => 2,
+        CreateMode::EphemeralSequential => 3,
+    }
+}
+
+fn mode_from_code(code: u8) -> Option<CreateMode> {
+    match code {
+        0 => Some(CreateMode::Persistent),
+        1 => Some(CreateMode::Ephemeral),
+        2 => Some(CreateMode::Sequential),
+        3 => Some(CreateMode::EphemeralSequential),
+        _ => None,
+    }
+}
+
+fn encode_tx(tx: &Transaction, out: &mut Vec<u8>) {
+    put_i64(tx.zxid, out);
+    out.push(action_code(&tx.action));
+    put_str(&tx.key, out);
+    put_str(&tx.val, out);
+    put_i32(tx.version, out);
+    out.push(mode_code(&tx.mode));
+    put_i64(tx.session_id, out);
+    put_i32(tx.timeout, out);
+    put_i64(tx.time, out);
+    put_i32(tx.ops.len() as i32, out);
+    for op in tx.ops.iter() {
+        encode_tx(op, out);
+    }
+}
+
+// advances input past the decoded tx, None if it's cut short or malformed
+fn decode_tx(input: &mut &[u8]) -> Option<Transaction> {
+    let mut tx = Transaction::new();
+    tx.zxid = take_i64(input)?;
+    tx.action = action_from_code(take_u8(input)?)?;
+    tx.key = take_str(input)?;
+    tx.val = take_str(input)?;
+    tx.version = take_i32(input)?;
+    tx.mode = mode_from_code(take_u8(input)?)?;
+    tx.session_id = take_i64(input)?;
+    tx.timeout = take_i32(input)?;
+    tx.time = take_i64(input)?;
+    for _ in 0..take_i32(input)? {
+        tx.ops.push(decode_tx(input)?);
+    }
+    Some(tx)
+}
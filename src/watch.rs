@@ -0,0 +1,136 @@
+use std::collections::{HashMap, HashSet};
+use tokio::sync::mpsc;
+
+
+#[allow(clippy::enum_variant_names)] // same names as ZooKeeper's event types
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EventType {
+    NodeCreated,
+    NodeDeleted,
+    NodeDataChanged,
+    NodeChildrenChanged,
+}
+
+// ZooKeeper 3.6 addWatch modes, neither is removed when it fires
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AddWatchMode {
+    Persistent, // data and child events of the path
+    PersistentRecursive, // data events of the path and every znode below it, no child events
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct WatchedEvent {
+    pub event_type: EventType,
+    pub path: String,
+}
+
+// Watches live on the server the client read from.
+// get/ exists register one shot data watches, get_children registers one shot child watches
+pub struct WatchManager {
+    data_watches: HashMap<String, HashSet<i64>>,
+    child_watches: HashMap<String, HashSet<i64>>,
+    persistent_watches: HashMap<String, HashSet<i64>>,
+    recursive_watches: HashMap<String, HashSet<i64>>,
+    clients: HashMap<i64, mpsc::UnboundedSender<WatchedEvent>>, // where each session's events go
+}
+
+impl WatchManager {
+    pub fn new() -> Self {
+        WatchManager {
+            data_watches: HashMap::new(),
+            child_watches: HashMap::new(),
+            persistent_watches: HashMap::new(),
+            recursive_watches: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+
+    pub fn add_data_watch(&mut self, path: &str, session_id: i64, client: mpsc::UnboundedSender<WatchedEvent>) {
+        self.clients.insert(session_id, client);
+        self.data_watches.entry(path.to_string()).or_default().insert(session_id);
+    }
+
+    pub fn add_child_watch(&mut self, path: &str, session_id: i64, client: mpsc::UnboundedSender<WatchedEvent>) {
+        self.clients.insert(session_id, client);
+        self.child_watches.entry(path.to_string()).or_default().insert(session_id);
+    }
+
+    pub fn add_watch(&mut self, path: &str, mode: AddWatchMode, session_id: i64, client: mpsc::UnboundedSender<WatchedEvent>) {
+        self.clients.insert(session_id, client);
+        let watches = match mode {
+            AddWatchMode::Persistent => &mut self.persistent_watches,
+            AddWatchMode::PersistentRecursive => &mut self.recursive_watches,
+        };
+        watches.entry(path.to_string()).or_default().insert(session_id);
+    }
+
+    // removes every kind of watch session_id has on path
+    pub fn remove_watches(&mut self, path: &str, session_id: i64) {
+        let all = [&mut self.data_watches, &mut self.child_watches, &mut self.persistent_watches, &mut self.recursive_watches];
+        for watches in all {
+            if let Some(watchers) = watches.get_mut(path) {
+                watchers.remove(&session_id);
+                if watchers.is_empty() {
+                    watches.remove(path);
+                }
+            }
+        }
+    }
+
+    // "/a/b" -> ["/a/b", "/a", "/"]
+    fn path_and_ancestors(path: &str) -> Vec<&str> {
+        let mut paths = vec![path];
+        let mut current = path;
+        while let Some(idx) = current.rfind('/') {
+            if current == "/" { break; }
+            current = if idx == 0 { "/" } else { &current[..idx] };
+            paths.push(current);
+        }
+        paths
+    }
+
+    // same mapping as ZooKeeper, e.g. a delete fires both the data and child watches of the path
+    pub fn trigger(&mut self, event_type: EventType, path: &str) {
+        let mut sessions = HashSet::new();
+        if let Some(watchers) = self.persistent_watches.get(path) {
+            sessions.extend(watchers.iter().cloned());
+        }
+        if event_type != EventType::NodeChildrenChanged {
+            for ancestor in WatchManager::path_and_ancestors(path) {
+                if let Some(watchers) = self.recursive_watches.get(ancestor) {
+                    sessions.extend(watchers.iter().cloned());
+                }
+            }
+        }
+        match event_type {
+            EventType::NodeCreated | EventType::NodeDataChanged => {
+                sessions.extend(self.data_watches.remove(path).unwrap_or_default());
+            }
+            EventType::NodeDeleted => {
+                sessions.extend(self.data_watches.remove(path).unwrap_or_default());
+                sessions.extend(self.child_watches.remove(path).unwrap_or_default());
+            }
+            EventType::NodeChildrenChanged => {
+                sessions.extend(self.child_watches.remove(path).unwrap_or_default());
+            }
+        }
+
+        for session_id in sessions {
+            if let Some(client) = self.clients.get(&session_id) {
+                let _ = client.send(WatchedEvent { event_type: event_type.clone(), path: path.to_string() });
+            }
+        }
+    }
+
+    // watches only last as long as the session that set them
+    pub fn remove_session(&mut self, session_id: i64) {
+        self.clients.remove(&session_id);
+        let all = [&mut self.data_watches, &mut self.child_watches, &mut self.persistent_watches, &mut self.recursive_watches];
+        for watches in all {
+            for watchers in watches.values_mut() {
+                watchers.remove(&session_id);
+            }
+            watches.retain(|_, watchers| !watchers.is_empty());
+        }
+    }
+}
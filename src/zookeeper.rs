@@ -1,53 +1,322 @@
-use crate::node::{Message, MessageType, Node, NodeStatus, Transaction, TxActions};
+use crate::data_tree::{CreateMode, Stat, ZkError};
+use crate::election::ElectionAlg;
+use crate::node::{Message, MessageType, Node, NodeStatus, Transaction, TxActions, ZabPhase};
+use crate::watch::{AddWatchMode, WatchedEvent};
 use futures::future::join_all;
 use rand::random;
 use std::collections::HashMap;
 use std::io;
 use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::{broadcast, Mutex};
+use tokio::sync::broadcast::error::TryRecvError;
+use tokio::sync::{broadcast, mpsc, Mutex, MutexGuard, OwnedMutexGuard};
 use tokio::task;
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout};
+
+const DEFAULT_DATA_DIR: &str = "zk_data";
+// how long a client waits on a write before it takes the connection for lost
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct Zookeeper {
     size: i32,
+    data_dir: PathBuf,
+    election_alg: ElectionAlg,
     pub servers: HashMap<i32, Arc<Mutex<Node>>>,
     pub sender: broadcast::Sender<Message>,
     pub receiver: broadcast::Receiver<Message>,
     pub latest_leader: i32,
+    // every session opened here gets a channel its watch events are delivered on
+    watch_channels: HashMap<i64, (mpsc::UnboundedSender<WatchedEvent>, mpsc::UnboundedReceiver<WatchedEvent>)>,
+    opened: HashMap<i64, i64>, // the zxid each of those sessions was opened under
 }
 
 impl Zookeeper {
+    // every server has to run the same one, so it's picked before they start
+    pub async fn set_election_alg(&mut self, election_alg: ElectionAlg) {
+        self.election_alg = election_alg;
+        for node in self.servers.values() {
+            node.lock().await.election_alg = election_alg;
+        }
+    }
+
     pub fn start_servers(&mut self) -> Vec<JoinHandle<()>> {
         println!("Starting zookeeper");
         let mut handlers = vec![];
 
-        for (id, node) in self.servers.iter_mut() {
+        for node in self.servers.values_mut() {
             let handler = task::spawn(Node::run(node.clone()));
             handlers.push(handler);
         }
         handlers
     }
 
-    pub fn new_transaction(&mut self, key: String, val: String) {
-        let mut tx = Transaction::new();
-        tx.action = TxActions::New;
-        tx.key = key;
-        tx.val = val;
+    // the node's tasks exit on their next loop, this waits until all of them let go of it
+    pub async fn stop_server(&mut self, server_id: i32) {
+        let node = self.servers[&server_id].clone();
+        {
+            let mut n = node.lock().await;
+            if n.status != NodeStatus::Quiting {
+                println!("stopping server {}", server_id);
+                let phase = n.phase;
+                Node::set_state(&mut n, NodeStatus::Quiting, phase);
+            }
+        }
+        while Arc::strong_count(&node) > 2 {
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    // brings server_id back from its data dir alone, it rejoins through discovery and sync like any follower.
+    // Stops it first if it's still running, the returned handle finishes once it quits again
+    pub async fn restart_server(&mut self, server_id: i32) -> JoinHandle<()> {
+        self.stop_server(server_id).await;
+        println!("restarting server {}", server_id);
+        let mut node = Node::new(server_id, self.sender.clone(), self.sender.subscribe(), self.size, &self.data_dir);
+        node.election_alg = self.election_alg;
+        node.observer = server_id >= self.size;
+        let node = Arc::new(Mutex::new(node));
+        self.servers.insert(server_id, node.clone());
+        task::spawn(Node::run(node))
+    }
 
-        let mut update = Message::new(-2, MessageType::Write);
-        update.receiver_id = self.latest_leader;
+    // an extra server past the voting ones, it follows whoever leads without changing the quorum.
+    // Has to be added before start_servers, returns its id
+    pub fn add_observer(&mut self) -> i32 {
+        let observer_id = self.servers.len() as i32;
+        let mut node = Node::new(observer_id, self.sender.clone(), self.sender.subscribe(), self.size, &self.data_dir);
+        node.election_alg = self.election_alg;
+        node.observer = true;
+        self.servers.insert(observer_id, Arc::new(Mutex::new(node)));
+        observer_id
+    }
+
+    // resolves once the session is committed, with its id and the timeout the leader negotiated for it
+    pub async fn open_session(&mut self, timeout_ms: i32) -> Result<(i64, i32), ZkError> {
+        let session_id = (random::<u64>() >> 1) as i64 + 1;
+        let mut tx = Transaction::session_op(TxActions::CreateSession, session_id);
+        tx.timeout = timeout_ms;
+        let server_id = self.latest_leader;
+        let zxid = self.write_to(server_id, tx).await?;
+        // it's applied on the leader by now, unless it already expired again
+        let timeout = self.session_timeout(server_id, session_id).await.ok_or(ZkError::SessionExpired)?;
+        self.watch_channels.insert(session_id, mpsc::unbounded_channel());
+        self.opened.insert(session_id, zxid);
+        Ok((session_id, timeout))
+    }
+
+    // sessions that aren't pinged within their timeout are expired by the leader
+    pub fn ping(&mut self, session_id: i64) {
+        let mut ping = Message::new(-2, MessageType::Ping);
+        ping.receiver_id = self.latest_leader;
+        ping.tx.session_id = session_id;
+        self.sender.send(ping).unwrap();
+    }
+
+    pub async fn session_timeout(&self, server_id: i32, session_id: i64) -> Option<i32> {
+        let n = self.servers[&server_id].lock().await;
+        n.sessions.get(session_id).map(|session| session.timeout)
+    }
+
+    // resolves with the zxid the close committed under, its ephemerals are gone by then
+    pub async fn close_session(&mut self, session_id: i64) -> Result<i64, ZkError> {
+        self.watch_channels.remove(&session_id);
+        self.opened.remove(&session_id);
+        self.write(Transaction::session_op(TxActions::CloseSession, session_id)).await
+    }
+
+    // the returned receiver gets the result
+    fn send_to(&self, server_id: i32, msg_type: MessageType, tx: Transaction) -> mpsc::UnboundedReceiver<Result<i64, ZkError>> {
+        let (reply, result) = mpsc::unbounded_channel();
+        let mut update = Message::new(-2, msg_type);
+        update.receiver_id = server_id;
         update.tx = tx;
+        update.reply = Some(reply);
 
         self.sender.send(update).unwrap();
+        result
+    }
+
+    pub async fn write(&self, tx: Transaction) -> Result<i64, ZkError> {
+        self.write_to(self.latest_leader, tx).await
+    }
+
+    pub async fn new_transaction(&self, key: String, val: String) -> Result<i64, ZkError> {
+        self.create(&key, &val).await
+    }
+
+    pub async fn create(&self, path: &str, data: &str) -> Result<i64, ZkError> {
+        self.write(Transaction::create_op(path, data)).await
+    }
+
+    // ephemeral znodes belong to session_id and go away once it closes
+    pub async fn create_with_mode(&self, path: &str, data: &str, mode: CreateMode, session_id: i64) -> Result<i64, ZkError> {
+        self.write(Transaction::create_mode_op(path, data, mode, session_id)).await
+    }
+
+    // expected_version of -1 skips the version check
+    pub async fn set(&self, path: &str, data: &str, expected_version: i32) -> Result<i64, ZkError> {
+        self.write(Transaction::set_op(path, data, expected_version)).await
+    }
+
+    pub async fn delete(&self, path: &str, expected_version: i32) -> Result<i64, ZkError> {
+        self.write(Transaction::delete_op(path, expected_version)).await
+    }
+
+    // ops are built with Transaction::create_op/ set_op/ delete_op/ check_op, all succeed or none do
+    pub async fn multi(&self, ops: Vec<Transaction>) -> Result<i64, ZkError> {
+        self.write(Transaction::multi(ops)).await
+    }
+
+    // resolves once the write is committed and applied by server_id, with the zxid it got, or with why it failed.
+    // A follower passes it on to its leader, and holds on to it while there's no leader yet.
+    // ConnectionLoss if server_id lost its leader or never answered, the write may still commit
+    pub async fn write_to(&self, server_id: i32, tx: Transaction) -> Result<i64, ZkError> {
+        let result = self.send_to(server_id, MessageType::Write, tx);
+        Zookeeper::wait_for(result).await
+    }
+
+    // resolves once server_id applied everything its leader had committed when it got the sync, with that zxid,
+    // so reads from server_id after it see every write that finished before it. Like ZooKeeper's, path doesn't
+    // narrow it down, the whole tree catches up. ConnectionLoss if server_id isn't serving or loses its leader
+    pub async fn sync(&self, server_id: i32, path: &str) -> Result<i64, ZkError> {
+        let mut tx = Transaction::new();
+        tx.key = path.to_string();
+        let result = self.send_to(server_id, MessageType::Sync, tx);
+        Zookeeper::wait_for(result).await
+    }
+
+    async fn wait_for(mut result: mpsc::UnboundedReceiver<Result<i64, ZkError>>) -> Result<i64, ZkError> {
+        match timeout(WRITE_TIMEOUT, result.recv()).await {
+            Ok(Some(result)) => result,
+            _ => Err(ZkError::ConnectionLoss),
+        }
+    }
+
+    // a server that isn't up to date with its leader turns clients away with ConnectionLoss, see Node::is_serving
+    async fn serving(&self, server_id: i32) -> Result<OwnedMutexGuard<Node>, ZkError> {
+        let n = self.servers[&server_id].clone().lock_owned().await;
+        if Node::is_serving(&n) {
+            return Ok(n);
+        }
+        println!("server {} isn't serving clients until it's up to date", server_id);
+        Err(ZkError::ConnectionLoss)
+    }
+
+    // reads are served by server_id alone, from its committed state
+    pub async fn get(&self, server_id: i32, path: &str) -> Result<Option<(String, Stat)>, ZkError> {
+        let n = self.serving(server_id).await?;
+        Ok(n.data.get_data(path))
+    }
+
+    pub async fn exists(&self, server_id: i32, path: &str) -> Result<Option<Stat>, ZkError> {
+        let n = self.serving(server_id).await?;
+        Ok(n.data.stat(path))
+    }
+
+    // the lease read mode, served by the latest leader alone while its lease holds, which makes them linearizable
+    // without a sync. ConnectionLoss when it doesn't hold one, see Node::has_lease, None when path doesn't exist
+    pub async fn get_leased(&self, path: &str) -> Result<Option<(String, Stat)>, ZkError> {
+        let n = self.leased().await?;
+        Ok(n.data.get_data(path))
+    }
+
+    async fn leased(&self) -> Result<MutexGuard<'_, Node>, ZkError> {
+        let n = self.servers.get(&self.latest_leader).ok_or(ZkError::ConnectionLoss)?.lock().await;
+        if Node::has_lease(&n) {
+            return Ok(n);
+        }
+        println!("server {} doesn't hold a leader lease", self.latest_leader);
+        Err(ZkError::ConnectionLoss)
+    }
+
+    // NoNode when path doesn't exist, like DataTree::get_children
+    pub async fn get_children(&self, server_id: i32, path: &str) -> Result<Vec<String>, ZkError> {
+        let n = self.serving(server_id).await?;
+        n.data.get_children(path)
+    }
+
+    // where session_id's watch events go, SessionExpired for a session that was never opened here or is closed
+    fn watcher(&self, session_id: i64) -> Result<mpsc::UnboundedSender<WatchedEvent>, ZkError> {
+        match self.watch_channels.get(&session_id) {
+            Some((sender, _)) => Ok(sender.clone()),
+            None => Err(ZkError::SessionExpired),
+        }
+    }
+
+    // server_id, as long as it's serving and knows session_id. A session the leader expired is gone from server_id
+    // once it applied that, a watch left for it would never be cleared. One that didn't apply the open yet
+    // answers ConnectionLoss, a sync catches it up
+    async fn watching(&mut self, server_id: i32, session_id: i64) -> Result<(OwnedMutexGuard<Node>, mpsc::UnboundedSender<WatchedEvent>), ZkError> {
+        let watcher = self.watcher(session_id)?;
+        let n = self.serving(server_id).await?;
+        if n.sessions.get(session_id).is_none() {
+            if n.last_applied < self.opened.get(&session_id).copied().unwrap_or(0) {
+                println!("server {} didn't apply session {} yet", server_id, session_id);
+                return Err(ZkError::ConnectionLoss);
+            }
+            println!("server {} doesn't know session {}, it expired", server_id, session_id);
+            self.watch_channels.remove(&session_id);
+            self.opened.remove(&session_id);
+            return Err(ZkError::SessionExpired);
+        }
+        Ok((n, watcher))
+    }
+
+    // the *_and_watch reads leave a one shot watch for session_id on server_id
+    pub async fn get_and_watch(&mut self, server_id: i32, session_id: i64, path: &str) -> Result<Option<(String, Stat)>, ZkError> {
+        let (mut n, watcher) = self.watching(server_id, session_id).await?;
+        let result = n.data.get_data(path);
+        if result.is_some() {
+            n.watches.add_data_watch(path, session_id, watcher);
+        }
+        Ok(result)
+    }
+
+    // also watches znodes that don't exist yet, to hear about their creation
+    pub async fn exists_and_watch(&mut self, server_id: i32, session_id: i64, path: &str) -> Result<Option<Stat>, ZkError> {
+        let (mut n, watcher) = self.watching(server_id, session_id).await?;
+        n.watches.add_data_watch(path, session_id, watcher);
+        Ok(n.data.stat(path))
+    }
+
+    pub async fn get_children_and_watch(&mut self, server_id: i32, session_id: i64, path: &str) -> Result<Vec<String>, ZkError> {
+        let (mut n, watcher) = self.watching(server_id, session_id).await?;
+        let result = n.data.get_children(path)?;
+        n.watches.add_child_watch(path, session_id, watcher);
+        Ok(result)
+    }
+
+    // persistent watches keep firing until removed or the session closes
+    pub async fn add_watch(&mut self, server_id: i32, session_id: i64, path: &str, mode: AddWatchMode) -> Result<(), ZkError> {
+        let (mut n, watcher) = self.watching(server_id, session_id).await?;
+        n.watches.add_watch(path, mode, session_id, watcher);
+        Ok(())
+    }
+
+    pub async fn remove_watches(&self, server_id: i32, session_id: i64, path: &str) -> Result<(), ZkError> {
+        let mut n = self.serving(server_id).await?;
+        n.watches.remove_watches(path, session_id);
+        Ok(())
+    }
+
+    // events delivered to session_id since the last poll
+    pub fn poll_events(&mut self, session_id: i64) -> Vec<WatchedEvent> {
+        let mut events = Vec::new();
+        if let Some((_, receiver)) = self.watch_channels.get_mut(&session_id) {
+            while let Ok(event) = receiver.try_recv() {
+                events.push(event);
+            }
+        }
+        events
     }
 
     pub async fn crash_leader(&mut self) {
         println!("leader {} is gonna crash", self.latest_leader);
         let mut leader = self.servers[&self.latest_leader].lock().await;
-        leader.status = NodeStatus::Following;
+        Node::set_state(&mut leader, NodeStatus::Looking, ZabPhase::Election);
 
         while !leader.history.is_empty() {
             leader.history.pop();
@@ -61,24 +330,23 @@ impl Zookeeper {
         // first acquire all the locks so it can report at once
         let mut locks = Vec::new();
 
-        for (k, v) in self.servers.iter() {
+        for v in self.servers.values() {
             locks.push(v.lock().await);
         }
 
         for n in locks.iter() {
-            Node::node_report(&n);
+            Node::node_report(n);
         }
     }
 
     pub async fn quit(&mut self) {
         let mut locks = Vec::new();
 
-        for (k, v) in self.servers.iter() {
+        for v in self.servers.values() {
             locks.push(v.lock().await);
         }
-        // should save all nodes
-
-        println!("saving & quiting zookeeper");
+        // nothing to save, every node logs its transactions as they come in
+        println!("quiting zookeeper");
         sleep(Duration::from_secs(3)).await;
         let mut msg = Message::new(-2, MessageType::Quit);
         msg.receiver_id = -1;
@@ -86,24 +354,62 @@ impl Zookeeper {
     }
 
     pub async fn find_latest_leader(&mut self) {
-        while !self.receiver.is_empty() {
-            let msg = self.receiver.recv().await.unwrap();
-            if msg.msg_type == MessageType::Heartbeat {
-                self.latest_leader = msg.sender_id;
+        loop {
+            match self.receiver.try_recv() {
+                Ok(msg) if msg.msg_type == MessageType::Heartbeat => self.latest_leader = msg.sender_id,
+                Ok(_) => {}
+                // it's only read now and then, whatever it fell behind on is old news
+                Err(TryRecvError::Lagged(_)) => {}
+                Err(_) => break,
             }
         }
     }
 
+    // e.g. "n /a x, s /b y 1, d /c -1, k /d 2"
+    fn parse_multi(input: &str) -> Option<Vec<Transaction>> {
+        let mut ops = Vec::new();
+        for op in input.split(',') {
+            let args: Vec<_> = op.split_whitespace().collect();
+            let version = |i: usize| args.get(i).and_then(|v| v.parse::<i32>().ok()).unwrap_or(-1);
+            let tx = match (args.first(), args.get(1)) {
+                (Some(&"n"), Some(path)) => Transaction::create_op(path, args.get(2).unwrap_or(&"")),
+                (Some(&"s"), Some(path)) => Transaction::set_op(path, args.get(2).unwrap_or(&""), version(3)),
+                (Some(&"d"), Some(path)) => Transaction::delete_op(path, version(2)),
+                (Some(&"k"), Some(path)) => Transaction::check_op(path, version(2)),
+                _ => return None,
+            };
+            ops.push(tx);
+        }
+        Some(ops)
+    }
+
     pub async fn run(&mut self) {
-        let handlers = self.start_servers();
+        let mut handlers = self.start_servers();
 
         // All nodes id starts from 0, receiver's id = -1 is for broadcast to all, sender == -2 is from Zookeeper
 
         let prompt = String::from("\n\
             *************\n\
             enter user input\n\
-            -n, [Key], [Val] for new Value\n\
-            -d, [Key] for delete;\n\
+            -n, [Path], [Data], [Mode], [Session] to create a znode\n\
+                mode is p (default), e, s or es, ephemeral modes need a session\n\
+            -o, [Timeout] to open a session, timeout in ms\n\
+            -p, [Session] to ping a session\n\
+            -x, [Session] to close a session\n\
+            -s, [Path], [Data], [Version] to set a znode's data, version is optional\n\
+            -d, [Path], [Version] for delete, version is optional;\n\
+            -m, [Op], [Op]... for an atomic multi, ops are comma separated:\n\
+                n [Path] [Data], s [Path] [Data] [Version], d [Path] [Version], k [Path] [Version]\n\
+            -g, [Server], [Path] to read a znode from a server\n\
+            -e, [Server], [Path] to check if a znode exists on a server\n\
+            -l, [Server], [Path] to list a znode's children on a server\n\
+            -y, [Server], [Path] to sync a server with the leader before reading from it\n\
+            -a, [Path] to read a znode from the leader while it holds its lease\n\
+            -w, [Server], [Session], [Path], [Kind] to watch a znode on a server\n\
+                kind is d (data, default), e (exists), c (children), p (persistent) or r (persistent recursive)\n\
+            -u, [Server], [Session], [Path] to remove a session's watches on a znode\n\
+            -k, [Server] to stop a server\n\
+            -b, [Server] to restart a server from its data dir\n\
             -c to crash the leader\n\
             -r to report on all nodes\n\
             -q to quit Zookeeper\n\
@@ -111,6 +417,12 @@ impl Zookeeper {
 
         loop {
             self.find_latest_leader().await;
+            let session_ids: Vec<i64> = self.watch_channels.keys().cloned().collect();
+            for session_id in session_ids {
+                for event in self.poll_events(session_id) {
+                    println!("session {}: {:?} {}", session_id, event.event_type, event.path);
+                }
+            }
 
             println!("{}", prompt);
             io::stdout().flush().unwrap();
@@ -121,7 +433,110 @@ impl Zookeeper {
             match parts[0] {
                 // e.g. -n new_key new_val
                 "-n" => {
-                    self.new_transaction(parts[1].to_string(), parts[2].to_string())
+                    let mode = match parts.get(3) {
+                        Some(&"e") => CreateMode::Ephemeral,
+                        Some(&"s") => CreateMode::Sequential,
+                        Some(&"es") => CreateMode::EphemeralSequential,
+                        _ => CreateMode::Persistent,
+                    };
+                    let session_id = parts.get(4).and_then(|v| v.parse::<i64>().ok()).unwrap_or(0);
+                    let result = match mode {
+                        CreateMode::Persistent => self.new_transaction(parts[1].to_string(), parts[2].to_string()).await,
+                        _ => self.create_with_mode(parts[1], parts[2], mode, session_id).await,
+                    };
+                    println!("{:?}", result);
+                }
+
+                "-o" => {
+                    let timeout = parts.get(1).and_then(|v| v.parse::<i32>().ok()).unwrap_or(10000);
+                    match self.open_session(timeout).await {
+                        Ok((session_id, timeout)) => println!("session {}, timeout {}", session_id, timeout),
+                        Err(e) => println!("{:?}", e),
+                    }
+                }
+
+                "-p" | "-x" => {
+                    match parts[1].parse::<i64>() {
+                        Ok(session_id) if parts[0] == "-p" => self.ping(session_id),
+                        Ok(session_id) => println!("{:?}", self.close_session(session_id).await),
+                        Err(_) => println!("invalid session {}", parts[1]),
+                    }
+                }
+
+                "-s" => {
+                    let version = parts.get(3).and_then(|v| v.parse::<i32>().ok()).unwrap_or(-1);
+                    println!("{:?}", self.set(parts[1], parts[2], version).await);
+                }
+
+                "-d" => {
+                    let version = parts.get(2).and_then(|v| v.parse::<i32>().ok()).unwrap_or(-1);
+                    println!("{:?}", self.delete(parts[1], version).await);
+                }
+
+                "-m" => {
+                    match Zookeeper::parse_multi(&user_input.trim()[2..]) {
+                        Some(ops) => println!("{:?}", self.multi(ops).await),
+                        None => println!("invalid multi op"),
+                    }
+                }
+
+                "-y" => {
+                    let server_id = parts[1].parse::<i32>().unwrap_or(-1);
+                    if !self.servers.contains_key(&server_id) {
+                        println!("unknown server {}", parts[1]);
+                        continue;
+                    }
+                    println!("{:?}", self.sync(server_id, parts.get(2).unwrap_or(&"/")).await);
+                }
+
+                "-a" => {
+                    println!("{:?}", self.get_leased(parts[1]).await);
+                }
+
+                "-w" | "-u" => {
+                    let server_id = parts[1].parse::<i32>().unwrap_or(-1);
+                    let session_id = parts[2].parse::<i64>().unwrap_or(0);
+                    if !self.servers.contains_key(&server_id) {
+                        println!("unknown server {}", parts[1]);
+                        continue;
+                    }
+                    if parts[0] == "-u" {
+                        println!("{:?}", self.remove_watches(server_id, session_id, parts[3]).await);
+                        continue;
+                    }
+                    match parts.get(4) {
+                        Some(&"p") => println!("{:?}", self.add_watch(server_id, session_id, parts[3], AddWatchMode::Persistent).await),
+                        Some(&"r") => println!("{:?}", self.add_watch(server_id, session_id, parts[3], AddWatchMode::PersistentRecursive).await),
+                        Some(&"e") => println!("{:?}", self.exists_and_watch(server_id, session_id, parts[3]).await),
+                        Some(&"c") => println!("{:?}", self.get_children_and_watch(server_id, session_id, parts[3]).await),
+                        _ => println!("{:?}", self.get_and_watch(server_id, session_id, parts[3]).await),
+                    }
+                }
+
+                "-g" | "-e" | "-l" => {
+                    let server_id = parts[1].parse::<i32>().unwrap_or(-1);
+                    if !self.servers.contains_key(&server_id) {
+                        println!("unknown server {}", parts[1]);
+                        continue;
+                    }
+                    match parts[0] {
+                        "-g" => println!("{:?}", self.get(server_id, parts[2]).await),
+                        "-e" => println!("{:?}", self.exists(server_id, parts[2]).await),
+                        _ => println!("{:?}", self.get_children(server_id, parts[2]).await),
+                    }
+                }
+
+                "-k" | "-b" => {
+                    let server_id = parts[1].parse::<i32>().unwrap_or(-1);
+                    if !self.servers.contains_key(&server_id) {
+                        println!("unknown server {}", parts[1]);
+                        continue;
+                    }
+                    if parts[0] == "-k" {
+                        self.stop_server(server_id).await;
+                    } else {
+                        handlers.push(self.restart_server(server_id).await);
+                    }
                 }
 
                 "-c" => {
@@ -149,6 +564,11 @@ impl Zookeeper {
 
 impl Zookeeper {
     pub fn new(size: i32) -> Zookeeper {
+        Zookeeper::with_data_dir(size, Path::new(DEFAULT_DATA_DIR))
+    }
+
+    // each node keeps its transaction log under data_dir/node_<id>, and starts from it if it's already there
+    pub fn with_data_dir(size: i32, data_dir: &Path) -> Zookeeper {
         let mut servers = HashMap::new();
         let (sender, receiver) = broadcast::channel((size * size * size) as usize);
 
@@ -158,11 +578,11 @@ impl Zookeeper {
             let mut unique_id = i;
             while servers.contains_key(&unique_id) { unique_id = random::<i32>(); }
 
-            let node = Node::new(unique_id, sender.clone(), sender.subscribe(), size);
+            let node = Node::new(unique_id, sender.clone(), sender.subscribe(), size, data_dir);
             servers.insert(unique_id, Arc::new(Mutex::new(node)));
         }
 
-        Zookeeper { size, servers, sender, receiver, latest_leader: -5 }
+        Zookeeper { size, data_dir: data_dir.to_path_buf(), election_alg: ElectionAlg::Fast, servers, sender, receiver, latest_leader: -5, watch_channels: HashMap::new(), opened: HashMap::new() }
     }
 }
 